@@ -0,0 +1,236 @@
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{collection::Person, AppState, Error};
+
+/// Sorted set holding every audit record, scored by the unix time in
+/// milliseconds it was recorded at. Records are never trimmed.
+const AUDIT_KEY: &str = "audit:records";
+/// How many records a query reads from Redis at a time.
+const QUERY_BATCH_SIZE: isize = 1000;
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    View,
+    Upload,
+    DeleteCollection,
+    DeleteImage,
+    PlaceHold,
+    ReleaseHold,
+}
+
+impl Action {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::View => "view",
+            Self::Upload => "upload",
+            Self::DeleteCollection => "delete_collection",
+            Self::DeleteImage => "delete_image",
+            Self::PlaceHold => "place_hold",
+            Self::ReleaseHold => "release_hold",
+        }
+    }
+}
+
+impl Action {
+    #[must_use]
+    pub const fn legal_hold(held: bool) -> Self {
+        if held {
+            Self::PlaceHold
+        } else {
+            Self::ReleaseHold
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    pub actor: Option<Person>,
+    pub action: Action,
+    pub collection: String,
+    pub seq: Option<u64>,
+}
+
+impl Record {
+    #[must_use]
+    pub fn new(actor: Option<Person>, action: Action, collection: &str) -> Self {
+        Self {
+            at: OffsetDateTime::now_utc(),
+            actor,
+            action,
+            collection: collection.to_owned(),
+            seq: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    const fn score(&self) -> i64 {
+        score(self.at)
+    }
+
+    #[must_use]
+    pub fn at_string(&self) -> String {
+        crate::format_time(self.at)
+    }
+}
+
+/// Filters for looking through the audit log. Empty strings are ignored, so a
+/// plain HTML form can be submitted with blank fields.
+#[derive(Deserialize, Default, Debug)]
+pub struct Query {
+    #[serde(default)]
+    pub collection: String,
+    #[serde(default)]
+    pub actor: String,
+    #[serde(default)]
+    pub action: String,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    fn matches(&self, record: &Record) -> bool {
+        let collection = self.collection.trim();
+        let actor = self.actor.trim();
+        let action = self.action.trim();
+        (collection.is_empty() || record.collection == collection)
+            && (actor.is_empty()
+                || record
+                    .actor
+                    .as_ref()
+                    .is_some_and(|p| p.id.to_string() == actor || p.name == actor))
+            && (action.is_empty() || record.action.as_str() == action)
+    }
+}
+
+#[instrument(skip_all, fields(action = ?record.action, collection = record.collection))]
+pub async fn record(state: &AppState, record: &Record) -> Result<(), Error> {
+    let json = serde_json::to_string(record)?;
+    let _: () = state
+        .redis
+        .clone()
+        .zadd(AUDIT_KEY, json, record.score())
+        .await?;
+    info!(
+        actor = ?record.actor.as_ref().map(|p| p.id),
+        seq = record.seq,
+        "Recorded audit event"
+    );
+    Ok(())
+}
+
+/// For actions that have already happened, where failing the request would
+/// only make it look like they didn't. A failed write is logged instead.
+pub async fn record_or_log(state: &AppState, record: &Record) {
+    if let Err(source) = self::record(state, record).await {
+        error!(?source, ?record, "Failed to write audit record");
+    }
+}
+
+/// Most recent records matching `query`, newest first. The whole log is
+/// searched, a batch at a time, until enough records are found.
+#[instrument(skip(state))]
+pub async fn query(state: &AppState, query: &Query) -> Result<Vec<Record>, Error> {
+    let mut redis = state.redis.clone();
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    // Records added while searching would shift the batches, so stop at now.
+    let newest = score(OffsetDateTime::now_utc());
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while records.len() < limit {
+        let raw: Vec<String> = redis
+            .zrevrangebyscore_limit(AUDIT_KEY, newest, "-inf", offset, QUERY_BATCH_SIZE)
+            .await?;
+        let exhausted = raw.len() < QUERY_BATCH_SIZE.unsigned_abs();
+        offset += QUERY_BATCH_SIZE;
+        records.extend(
+            raw.iter()
+                .filter_map(|json| match serde_json::from_str::<Record>(json) {
+                    Ok(record) => Some(record),
+                    Err(source) => {
+                        warn!(?source, "Skipping unreadable audit record");
+                        None
+                    }
+                })
+                .filter(|record| query.matches(record))
+                .take(limit - records.len()),
+        );
+        if exhausted {
+            break;
+        }
+    }
+    Ok(records)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn score(at: OffsetDateTime) -> i64 {
+    (at.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::id::Id;
+
+    use super::*;
+
+    fn record() -> Record {
+        let actor = Person {
+            id: Id::new(42),
+            name: "moderator".to_owned(),
+        };
+        Record::new(Some(actor), Action::DeleteImage, "abc").seq(3)
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert!(Query::default().matches(&record()));
+    }
+
+    #[test]
+    fn query_matches_actor_by_id_or_name() {
+        for actor in ["42", "moderator", " moderator "] {
+            let query = Query {
+                actor: actor.to_owned(),
+                ..Query::default()
+            };
+            assert!(query.matches(&record()), "{actor}");
+        }
+        let query = Query {
+            actor: "43".to_owned(),
+            ..Query::default()
+        };
+        assert!(!query.matches(&record()));
+    }
+
+    #[test]
+    fn query_needs_every_filter_to_match() {
+        let query = Query {
+            collection: "abc".to_owned(),
+            action: "delete_collection".to_owned(),
+            ..Query::default()
+        };
+        assert!(!query.matches(&record()));
+    }
+
+    #[test]
+    fn records_are_scored_in_milliseconds() {
+        let at = OffsetDateTime::from_unix_timestamp_nanos(1_500_000_999_999).unwrap();
+        assert_eq!(score(at), 1_500_000);
+    }
+}
@@ -1,6 +1,9 @@
+use std::{convert::Infallible, sync::Arc};
+
+use askama::Template;
 use axum::{
-    extract::{Query, Request, State},
-    http::Uri,
+    extract::{FromRequestParts, OptionalFromRequestParts, Query, Request, State},
+    http::{request::Parts, Uri},
     middleware::Next,
     response::{IntoResponse, Redirect, Response},
 };
@@ -12,13 +15,139 @@ use oauth2::{
     basic::BasicTokenResponse, AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier,
     Scope, TokenResponse,
 };
-use redis::AsyncCommands;
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
 use serde::{Deserialize, Serialize};
-use time::Duration;
-use twilight_http::client::ClientBuilder;
-use twilight_model::{guild::Permissions, id::Id};
+use time::{Duration, OffsetDateTime};
+use tower_sombrero::csp::CspNonce;
+use twilight_http::{
+    api_error::{ApiError, GeneralApiError},
+    client::ClientBuilder,
+    Client,
+};
+use twilight_model::{
+    guild::Permissions,
+    id::{
+        marker::{GuildMarker, RoleMarker, UserMarker},
+        Id,
+    },
+    user::CurrentUserGuild,
+    util::ImageHash,
+};
+use twilight_util::permission_calculator::PermissionCalculator;
+
+use crate::{collection::Person, AppState, Error, TemplateWrapper};
 
-use crate::{AppState, Error};
+/// What we remember about a logged-in user, stored at `token:auth:{token}`.
+/// Handlers can take it as an extractor behind [`middleware`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Session {
+    /// Identifies the session on the admin page without exposing its token.
+    pub id: String,
+    pub user: Person,
+    pub avatar: Option<ImageHash>,
+    /// The configured guilds the user is authorized in.
+    pub guilds: Vec<GuildAccess>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub logged_in_at: OffsetDateTime,
+    /// Last time the guild access above was confirmed with Discord.
+    #[serde(with = "time::serde::rfc3339")]
+    pub checked_at: OffsetDateTime,
+}
+
+/// A member's standing in one guild, as of the session's last check.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuildAccess {
+    pub id: Id<GuildMarker>,
+    pub name: String,
+    pub permissions: Permissions,
+    pub roles: Vec<Id<RoleMarker>>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Session {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Self>()
+            .cloned()
+            .ok_or(Error::Unauthorized)
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for Session {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<Self>().cloned())
+    }
+}
+
+impl Session {
+    #[must_use]
+    pub fn logged_in_at_string(&self) -> String {
+        crate::format_time(self.logged_in_at)
+    }
+
+    #[must_use]
+    pub fn avatar_url(&self) -> Option<String> {
+        self.avatar.map(|hash| {
+            format!(
+                "https://cdn.discordapp.com/avatars/{}/{hash}.png?size=64",
+                self.user.id
+            )
+        })
+    }
+
+    #[must_use]
+    pub fn is_authorized_in(&self, guild: Id<GuildMarker>) -> bool {
+        self.guilds.iter().any(|access| access.id == guild)
+    }
+
+    /// Collections without a guild predate multi-guild support, so every
+    /// session may use them.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn require_guild(&self, guild: Option<Id<GuildMarker>>) -> Result<(), Error> {
+        if guild.is_none_or(|guild| self.is_authorized_in(guild)) {
+            Ok(())
+        } else {
+            Err(Error::NoPermissions)
+        }
+    }
+
+    /// For collections whose guild can't be told, such as one with a damaged
+    /// manifest.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn require_every_guild(&self, state: &AppState) -> Result<(), Error> {
+        if state
+            .guilds
+            .iter()
+            .all(|&guild| self.is_authorized_in(guild))
+        {
+            Ok(())
+        } else {
+            self.require_admin(state)
+        }
+    }
+
+    #[allow(clippy::missing_errors_doc)]
+    pub fn require_admin(&self, state: &AppState) -> Result<(), Error> {
+        if state.admins.contains(&self.user.id) {
+            Ok(())
+        } else {
+            Err(Error::NoPermissions)
+        }
+    }
+}
+
+const SESSION_TTL: u64 = 86400;
+/// How long a session's permissions are trusted before asking Discord again.
+const RECHECK_INTERVAL: Duration = Duration::minutes(5);
+/// Set of every user ID that has had a session recently.
+const SESSION_USERS_KEY: &str = "sessions:users";
 
 #[derive(Serialize, Deserialize, Clone)]
 struct OAuth2RoundtripData {
@@ -30,17 +159,147 @@ pub async fn middleware(
     State(mut state): State<AppState>,
     cookies: CookieJar,
     uri: Uri,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> Response {
     let Some(token) = cookies.get("token") else {
         return oauthify(state, uri).await.into_response();
     };
-    let redis_key = format!("token:auth:{}", token.value());
-    match state.redis_exists(&redis_key).await {
-        Ok(true) => next.run(request).await,
-        Ok(false) => oauthify(state, uri).await.into_response(),
-        Err(e) => e.into_response(),
+    let session: Option<String> = match state.redis.get(session_key(token.value())).await {
+        Ok(session) => session,
+        Err(e) => return Error::from(e).into_response(),
+    };
+    // Sessions from before we stored who logged in don't parse, so make those log in again.
+    let Some(mut session) = session.and_then(|s| serde_json::from_str::<Session>(&s).ok()) else {
+        return oauthify(state, uri).await.into_response();
+    };
+    if session.checked_at + RECHECK_INTERVAL < OffsetDateTime::now_utc() {
+        // If Discord can't be reached, refuse this request but keep the session, so
+        // the next request tries again.
+        match recheck_session(&state, token.value(), &mut session).await {
+            Ok(true) => {}
+            Ok(false) => {
+                if let Err(source) = end_session(&state, token.value()).await {
+                    return source.into_response();
+                }
+                return Error::NoPermissions.into_response();
+            }
+            Err(source) => {
+                warn!(?source, "Failed to re-check session permissions");
+                return source.into_response();
+            }
+        }
+    }
+    request.extensions_mut().insert(session);
+    next.run(request).await
+}
+
+/// Refresh a session's roles and permissions in each of its guilds, dropping
+/// the ones the user no longer qualifies in. Returns `false` if none are left,
+/// or the session was ended while it was being checked, in which case it
+/// should be ended.
+#[instrument(skip(state, token, session), fields(user = %session.user.id))]
+async fn recheck_session(
+    state: &AppState,
+    token: &str,
+    session: &mut Session,
+) -> Result<bool, Error> {
+    let mut guilds = Vec::with_capacity(session.guilds.len());
+    for guild in &session.guilds {
+        if !state.guilds.contains(&guild.id) {
+            info!(guild = %guild.id, "Guild is no longer configured");
+            continue;
+        }
+        let Some(access) = fetch_member_access(state, guild.id, session.user.id).await? else {
+            info!(guild = %guild.id, "Session owner left the guild");
+            continue;
+        };
+        if !state
+            .policy
+            .allows(session.user.id, &access.roles, access.permissions)
+        {
+            info!(guild = %guild.id, permissions = ?access.permissions, "Session owner lost permissions");
+            continue;
+        }
+        guilds.push(GuildAccess {
+            id: guild.id,
+            name: guild.name.clone(),
+            permissions: access.permissions,
+            roles: access.roles,
+        });
+    }
+    if guilds.is_empty() {
+        return Ok(false);
+    }
+    session.guilds = guilds;
+    session.checked_at = OffsetDateTime::now_utc();
+    // Only update a session that still exists, so logging out or revoking it
+    // in the meantime isn't undone.
+    let options = SetOptions::default()
+        .conditional_set(ExistenceCheck::XX)
+        .with_expiration(SetExpiry::KEEPTTL);
+    let updated: bool = state
+        .redis
+        .clone()
+        .set_options(session_key(token), serde_json::to_string(session)?, options)
+        .await?;
+    Ok(updated)
+}
+
+/// A member's roles and guild-level permissions, as the bot sees them.
+pub struct MemberAccess {
+    pub roles: Vec<Id<RoleMarker>>,
+    pub permissions: Permissions,
+}
+
+/// Look a user up in a guild with the bot token. `None` means Discord said
+/// they are not a member; any other failure, including the bot not being in
+/// the guild, is an error.
+#[instrument(skip(state))]
+pub async fn fetch_member_access(
+    state: &AppState,
+    guild_id: Id<GuildMarker>,
+    user: Id<UserMarker>,
+) -> Result<Option<MemberAccess>, Error> {
+    let client = &state.discord.client;
+    let member = match client.guild_member(guild_id, user).await {
+        Ok(response) => response.model().await?,
+        Err(source) if is_unknown_member(&source) => return Ok(None),
+        Err(source) => return Err(source.into()),
+    };
+    let guild = client.guild(guild_id).await?.model().await?;
+    let everyone = guild
+        .roles
+        .iter()
+        .find(|role| role.id.cast() == guild_id)
+        .map_or_else(Permissions::empty, |role| role.permissions);
+    let member_roles: Vec<_> = guild
+        .roles
+        .iter()
+        .filter(|role| member.roles.contains(&role.id))
+        .map(|role| (role.id, role.permissions))
+        .collect();
+    let permissions = PermissionCalculator::new(guild_id, user, everyone, &member_roles)
+        .owner_id(guild.owner_id)
+        .root();
+    Ok(Some(MemberAccess {
+        roles: member.roles,
+        permissions,
+    }))
+}
+
+/// Discord's error codes for a user that isn't in the guild, or no longer
+/// exists. Other 404s, like an unknown guild, say nothing about the member.
+const UNKNOWN_MEMBER_CODES: [u64; 2] = [10007, 10013];
+
+fn is_unknown_member(error: &twilight_http::Error) -> bool {
+    match error.kind() {
+        twilight_http::error::ErrorType::Response {
+            status,
+            error: ApiError::General(GeneralApiError { code, .. }),
+            ..
+        } => status.get() == 404 && UNKNOWN_MEMBER_CODES.contains(code),
+        _ => false,
     }
 }
 
@@ -56,7 +315,7 @@ async fn oauthify(mut state: AppState, uri: Uri) -> Result<Redirect, Error> {
         .add_extra_param("integration_types", "1") // this was breaking it, and we disabled the command anyway
         .url();
     let roundtrip = OAuth2RoundtripData {
-        pkce: pkce_verifier.secret().to_string(),
+        pkce: pkce_verifier.secret().clone(),
         redirect: uri.path().to_string(),
     };
     let _: () = state
@@ -89,28 +348,56 @@ pub async fn authenticate(
         .await?;
     let token = format!("Bearer {}", token_response.access_token().secret());
     let client = ClientBuilder::new().token(token).build();
-    let guilds = client
-        .current_user_guilds()
-        .after(Id::new(state.guild.get() - 1))
-        .limit(1)
-        .await?
-        .model()
-        .await?;
+    let guilds = list_user_guilds(&client).await?;
+    let user = client.current_user().await?.model().await?;
 
     tokio::spawn(revoke_tokens(state.clone(), token_response));
 
-    let Some(guild) = guilds.first() else {
-        return Err(Error::NoPermissions);
-    };
-    if !guild.permissions.contains(Permissions::MODERATE_MEMBERS) || guild.id != state.guild {
+    let mut authorized = Vec::new();
+    for guild in guilds {
+        if !state.guilds.contains(&guild.id) {
+            continue;
+        }
+        // The OAuth2 guild listing doesn't include roles, so ask with the bot token.
+        // Without them denied roles can't be checked, so the guild is left out.
+        let roles = match fetch_member_access(&state, guild.id, user.id).await {
+            Ok(Some(access)) => access.roles,
+            Ok(None) => {
+                warn!(guild = %guild.id, "Member not found when fetching roles at login");
+                continue;
+            }
+            Err(source) => {
+                warn!(?source, guild = %guild.id, "Failed to fetch roles at login");
+                continue;
+            }
+        };
+        if state.policy.allows(user.id, &roles, guild.permissions) {
+            authorized.push(GuildAccess {
+                id: guild.id,
+                name: guild.name,
+                permissions: guild.permissions,
+                roles,
+            });
+        }
+    }
+    if authorized.is_empty() {
         return Err(Error::NoPermissions);
     }
 
+    let now = OffsetDateTime::now_utc();
+    let session = Session {
+        id: crate::randstring(16),
+        user: Person {
+            id: user.id,
+            name: user.name,
+        },
+        avatar: user.avatar,
+        guilds: authorized,
+        logged_in_at: now,
+        checked_at: now,
+    };
     let token = crate::randstring(64);
-    let _: () = state
-        .redis
-        .set_ex(format!("token:auth:{token}"), true, 86400)
-        .await?;
+    store_session(&state, &token, &session).await?;
     let cookie = Cookie::build(("token".to_owned(), token))
         .secure(true)
         .same_site(SameSite::Lax)
@@ -123,6 +410,149 @@ pub async fn authenticate(
     Ok((jar, Redirect::to(&roundtrip_data.redirect)))
 }
 
+/// Discord returns at most this many guilds per page.
+const GUILD_PAGE_SIZE: u16 = 200;
+
+/// Every guild the user is in, following pagination.
+async fn list_user_guilds(client: &Client) -> Result<Vec<CurrentUserGuild>, Error> {
+    let mut guilds: Vec<CurrentUserGuild> = Vec::new();
+    loop {
+        let mut request = client.current_user_guilds().limit(GUILD_PAGE_SIZE);
+        if let Some(last) = guilds.last() {
+            request = request.after(last.id);
+        }
+        let page = request.await?.model().await?;
+        let done = page.len() < usize::from(GUILD_PAGE_SIZE);
+        guilds.extend(page);
+        if done {
+            return Ok(guilds);
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "logged_out.hbs", escape = "html")]
+pub struct LoggedOut {
+    root_url: Arc<str>,
+    session: Option<Session>,
+    nonce: String,
+}
+
+/// Forget the session server-side and drop the cookie. This deliberately
+/// doesn't redirect home, as that would immediately log the user back in.
+pub async fn logout(
+    State(state): State<AppState>,
+    cookies: CookieJar,
+    CspNonce(nonce): CspNonce,
+) -> Result<(CookieJar, TemplateWrapper<LoggedOut>), Error> {
+    if let Some(token) = cookies.get("token") {
+        end_session(&state, token.value()).await?;
+    }
+    let jar = cookies.remove(Cookie::build("token").path("/"));
+    Ok((
+        jar,
+        TemplateWrapper(LoggedOut {
+            root_url: state.root_url,
+            session: None,
+            nonce,
+        }),
+    ))
+}
+
+fn session_key(token: &str) -> String {
+    format!("token:auth:{token}")
+}
+
+/// Hash of session ID to token for every live session a user has, so they can
+/// be listed and revoked without knowing the cookie.
+fn user_sessions_key(user: Id<UserMarker>) -> String {
+    format!("sessions:user:{user}")
+}
+
+async fn store_session(state: &AppState, token: &str, session: &Session) -> Result<(), Error> {
+    let mut redis = state.redis.clone();
+    let index = user_sessions_key(session.user.id);
+    let _: () = redis
+        .set_ex(
+            session_key(token),
+            serde_json::to_string(session)?,
+            SESSION_TTL,
+        )
+        .await?;
+    let _: () = redis.hset(&index, &session.id, token).await?;
+    let _: () = redis.expire(&index, SESSION_TTL.cast_signed()).await?;
+    let _: () = redis.sadd(SESSION_USERS_KEY, session.user.id.get()).await?;
+    Ok(())
+}
+
+async fn end_session(state: &AppState, token: &str) -> Result<(), Error> {
+    let mut redis = state.redis.clone();
+    let session: Option<String> = redis.get_del(session_key(token)).await?;
+    if let Some(session) = session.and_then(|s| serde_json::from_str::<Session>(&s).ok()) {
+        let _: () = redis
+            .hdel(user_sessions_key(session.user.id), &session.id)
+            .await?;
+    }
+    Ok(())
+}
+
+pub struct UserSessions {
+    pub user: Person,
+    pub sessions: Vec<Session>,
+}
+
+/// Every live session, grouped by user. Index entries for sessions that have
+/// already expired are cleaned up along the way.
+#[instrument(skip_all)]
+pub async fn list_sessions(state: &AppState) -> Result<Vec<UserSessions>, Error> {
+    let mut redis = state.redis.clone();
+    let users: Vec<u64> = redis.smembers(SESSION_USERS_KEY).await?;
+    let mut all = Vec::with_capacity(users.len());
+    for user in users {
+        let index = user_sessions_key(Id::new(user));
+        let tokens: Vec<(String, String)> = redis.hgetall(&index).await?;
+        let mut sessions = Vec::with_capacity(tokens.len());
+        for (id, token) in tokens {
+            let session: Option<String> = redis.get(session_key(&token)).await?;
+            match session.and_then(|s| serde_json::from_str::<Session>(&s).ok()) {
+                Some(session) => sessions.push(session),
+                None => redis.hdel(&index, &id).await?,
+            }
+        }
+        sessions.sort_by_key(|session| session.logged_in_at);
+        let Some(first) = sessions.first() else {
+            let _: () = redis.srem(SESSION_USERS_KEY, user).await?;
+            continue;
+        };
+        all.push(UserSessions {
+            user: first.user.clone(),
+            sessions,
+        });
+    }
+    all.sort_by(|a, b| a.user.name.cmp(&b.user.name));
+    Ok(all)
+}
+
+/// End one of a user's sessions, or all of them when `id` is `None`.
+#[instrument(skip(state))]
+pub async fn revoke_sessions(
+    state: &AppState,
+    user: Id<UserMarker>,
+    id: Option<&str>,
+) -> Result<(), Error> {
+    let mut redis = state.redis.clone();
+    let index = user_sessions_key(user);
+    let tokens: Vec<(String, String)> = redis.hgetall(&index).await?;
+    for (session_id, token) in tokens {
+        if id.is_none_or(|id| id == session_id) {
+            let _: () = redis.del(session_key(&token)).await?;
+            let _: () = redis.hdel(&index, &session_id).await?;
+            info!(%user, session_id, "Revoked session");
+        }
+    }
+    Ok(())
+}
+
 #[derive(serde::Deserialize)]
 pub struct SetIdQuery {
     code: String,
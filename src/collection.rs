@@ -0,0 +1,541 @@
+use std::{collections::BTreeMap, future::Future, ops::Range};
+
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use s3::error::S3Error;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use twilight_model::id::{
+    marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker},
+    Id,
+};
+
+use crate::{
+    sanitize::Metadata,
+    snapshot::{MessageSnapshot, ProfileSnapshot},
+    AppState, Error,
+};
+
+/// Everything we know about where a collection came from, stored next to its
+/// images at `{id}/manifest.json`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    /// Only members authorized in this guild may see or change the collection.
+    /// Collections saved before guilds were recorded have none.
+    #[serde(default)]
+    pub guild_id: Option<Id<GuildMarker>>,
+    pub uploader: Option<Person>,
+    pub source: Option<SourceMessage>,
+    /// The source message's text, author and embeds at the time it was saved.
+    #[serde(default)]
+    pub message: Option<MessageSnapshot>,
+    /// Set when the collection is a saved user profile rather than a message.
+    #[serde(default)]
+    pub profile: Option<ProfileSnapshot>,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentRecord>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Person {
+    pub id: Id<UserMarker>,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SourceMessage {
+    pub guild_id: Option<Id<GuildMarker>>,
+    pub channel_id: Id<ChannelMarker>,
+    pub message_id: Id<MessageMarker>,
+    pub author: Person,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AttachmentRecord {
+    pub seq: u64,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub size: u64,
+    #[serde(default)]
+    pub origin: Origin,
+    /// Where an embedded or linked image came from. Attachment URLs expire,
+    /// so they aren't kept.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Identifying metadata the image carried, which has been removed.
+    #[serde(default)]
+    pub metadata: Vec<Metadata>,
+    /// SHA-256 of the file as it was received, before its metadata was removed.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Which part of a message or profile an image was found in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    #[default]
+    Attachment,
+    Embed,
+    Sticker,
+    Profile,
+    Link,
+    /// The avatar of a saved message's author, shown beside the message.
+    Avatar,
+}
+
+impl Origin {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Attachment => "attachment",
+            Self::Embed => "embed",
+            Self::Sticker => "sticker",
+            Self::Profile => "profile",
+            Self::Link => "link",
+            Self::Avatar => "author avatar",
+        }
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Manifest {
+    #[must_use]
+    pub fn new(
+        state: &AppState,
+        guild_id: Option<Id<GuildMarker>>,
+        uploader: Option<Person>,
+    ) -> Self {
+        Self {
+            guild_id,
+            uploader,
+            source: None,
+            message: None,
+            profile: None,
+            attachments: Vec::new(),
+            created_at: OffsetDateTime::now_utc(),
+            expires_at: state.default_expiry(),
+        }
+    }
+
+    /// The manifest to store for a collection saved before manifests existed.
+    /// It was never given a retention, so it doesn't get one now.
+    #[must_use]
+    pub fn legacy(uploader: Option<Person>) -> Self {
+        Self {
+            guild_id: None,
+            uploader,
+            source: None,
+            message: None,
+            profile: None,
+            attachments: Vec::new(),
+            created_at: OffsetDateTime::now_utc(),
+            expires_at: None,
+        }
+    }
+
+    #[must_use]
+    pub fn created_at_string(&self) -> String {
+        crate::format_time(self.created_at)
+    }
+
+    #[must_use]
+    pub fn expires_at_string(&self) -> Option<String> {
+        self.expires_at.map(crate::format_time)
+    }
+
+    /// The author's avatar illustrates the saved message, rather than being
+    /// one of the collection's images.
+    #[must_use]
+    pub fn is_avatar(&self, seq: u64) -> bool {
+        self.attachments
+            .iter()
+            .any(|record| record.seq == seq && record.origin == Origin::Avatar)
+    }
+}
+
+impl SourceMessage {
+    #[must_use]
+    pub fn jump_url(&self) -> String {
+        jump_url(self.guild_id, self.channel_id, self.message_id)
+    }
+}
+
+#[must_use]
+pub fn jump_url(
+    guild_id: Option<Id<GuildMarker>>,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+) -> String {
+    let guild = guild_id.map_or_else(|| "@me".to_string(), |g| g.to_string());
+    format!("https://discord.com/channels/{guild}/{channel_id}/{message_id}")
+}
+
+/// How long a change to a collection may hold its lock.
+const LOCK_TTL_MILLIS: u64 = 10_000;
+/// How long to wait for the lock, long enough for an abandoned one to expire.
+const LOCK_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+const LOCK_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+/// Delete the lock only if it still holds our token, in one step so it can't
+/// expire and be taken by someone else in between.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+fn manifest_key(id: &str) -> String {
+    format!("{id}/manifest.json")
+}
+
+/// Only ever called with the collection's lock held, so that concurrent
+/// changes can't overwrite each other.
+async fn write_manifest(state: &AppState, id: &str, manifest: &Manifest) -> Result<(), Error> {
+    let json = serde_json::to_vec(manifest)?;
+    state
+        .bucket
+        .put_object_with_content_type(manifest_key(id), &json, "application/json")
+        .await?;
+    crate::retention::schedule(state, id, manifest.expires_at).await?;
+    Ok(())
+}
+
+/// Read, change and store a collection's manifest while holding its lock.
+/// `update` is given `None` if the collection has no manifest yet.
+#[instrument(skip(state, update))]
+pub async fn update_manifest<F>(state: &AppState, id: &str, update: F) -> Result<(), Error>
+where
+    F: FnOnce(Option<Manifest>) -> Result<Manifest, Error> + Send,
+{
+    locked(state, id, async {
+        let manifest = update(read_manifest(state, id).await?)?;
+        write_manifest(state, id, &manifest).await
+    })
+    .await
+}
+
+/// Run `change` while holding the collection's lock.
+async fn locked<T>(
+    state: &AppState,
+    id: &str,
+    change: impl Future<Output = Result<T, Error>> + Send,
+) -> Result<T, Error> {
+    let lock = lock_collection(state, id).await?;
+    let result = change.await;
+    unlock_collection(state, id, &lock).await?;
+    result
+}
+
+/// Add images to a collection's manifest, or store `manifest` as is if the
+/// collection doesn't have one yet.
+#[instrument(skip(state, manifest))]
+pub async fn append_to_manifest(
+    state: &AppState,
+    id: &str,
+    mut manifest: Manifest,
+) -> Result<(), Error> {
+    update_manifest(state, id, |existing| {
+        if let Some(mut existing) = existing {
+            existing.attachments.append(&mut manifest.attachments);
+            existing.attachments.sort_by_key(|record| record.seq);
+            manifest = existing;
+        }
+        Ok(manifest)
+    })
+    .await
+}
+
+/// Accept either a bare collection ID or a full link to its page.
+#[must_use]
+pub fn collection_id(input: &str) -> &str {
+    input
+        .trim()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+}
+
+/// Whether anything at all is stored for a collection.
+#[instrument(skip(state))]
+pub async fn exists(state: &AppState, id: &str) -> Result<bool, Error> {
+    Ok(!list_keys(state, &format!("{id}/")).await?.is_empty())
+}
+
+/// Reserve `count` sequence numbers in a collection, which may be appended
+/// to concurrently. The counter starts after the highest image already stored,
+/// so collections saved before it existed can be appended to as well.
+#[instrument(skip(state))]
+pub async fn allocate_seqs(state: &AppState, id: &str, count: u64) -> Result<Range<u64>, Error> {
+    let mut redis = state.redis.clone();
+    let key = seq_key(id);
+    let exists: bool = redis.exists(&key).await?;
+    if !exists {
+        let listing = list_collection(state, id).await?;
+        let stored = listing
+            .previews
+            .keys()
+            .chain(listing.originals.keys())
+            .max()
+            .map_or(0, |seq| seq + 1);
+        let recorded = read_manifest(state, id)
+            .await?
+            .and_then(|manifest| manifest.attachments.iter().map(|a| a.seq).max())
+            .map_or(0, |seq| seq + 1);
+        // Whoever gets here first seeds the counter, everyone else uses theirs.
+        let _: bool = redis.set_nx(&key, stored.max(recorded)).await?;
+    }
+    let end: u64 = redis.incr(&key, count).await?;
+    Ok(end - count..end)
+}
+
+fn seq_key(id: &str) -> String {
+    format!("collection:{id}:seq")
+}
+
+fn hold_key(id: &str) -> String {
+    format!("collection:{id}:hold")
+}
+
+fn lock_key(id: &str) -> String {
+    format!("collection:{id}:lock")
+}
+
+/// Wait for the collection's lock and return the token that releases it. The
+/// lock expires on its own, so a crashed holder can't block a collection.
+async fn lock_collection(state: &AppState, id: &str) -> Result<String, Error> {
+    let mut redis = state.redis.clone();
+    let token = crate::randstring(16);
+    let options = SetOptions::default()
+        .conditional_set(ExistenceCheck::NX)
+        .with_expiration(SetExpiry::PX(LOCK_TTL_MILLIS));
+    let deadline = tokio::time::Instant::now() + LOCK_WAIT;
+    loop {
+        let acquired: bool = redis.set_options(lock_key(id), &token, options).await?;
+        if acquired {
+            return Ok(token);
+        }
+        if tokio::time::Instant::now() >= deadline {
+            warn!(id, "Gave up waiting for collection lock");
+            return Err(Error::CollectionBusy);
+        }
+        tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+    }
+}
+
+/// Only release the lock if it's still ours.
+async fn unlock_collection(state: &AppState, id: &str, token: &str) -> Result<(), Error> {
+    let _: i64 = redis::cmd("EVAL")
+        .arg(UNLOCK_SCRIPT)
+        .arg(1)
+        .arg(lock_key(id))
+        .arg(token)
+        .query_async(&mut state.redis.clone())
+        .await?;
+    Ok(())
+}
+
+/// Collections created before manifests existed don't have one, so a missing
+/// object is `None` rather than an error.
+#[instrument(skip(state))]
+pub async fn read_manifest(state: &AppState, id: &str) -> Result<Option<Manifest>, Error> {
+    match state.bucket.get_object(manifest_key(id)).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(data.as_slice())?)),
+        Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a collection must be kept regardless of retention or manual deletion.
+#[instrument(skip(state))]
+pub async fn is_held(state: &AppState, id: &str) -> Result<bool, Error> {
+    Ok(state.redis.clone().exists(hold_key(id)).await?)
+}
+
+/// Place or release a legal hold. The hold lives in Redis rather than on the
+/// manifest, so it applies to collections without one and survives rewrites.
+#[instrument(skip(state))]
+pub async fn set_legal_hold(state: &AppState, id: &str, held: bool) -> Result<(), Error> {
+    if !exists(state, id).await? {
+        return Err(Error::NotFound);
+    }
+    let mut redis = state.redis.clone();
+    if held {
+        let _: () = redis.set(hold_key(id), true).await?;
+    } else {
+        let _: () = redis.del(hold_key(id)).await?;
+    }
+    info!(id, held, "Changed legal hold");
+    Ok(())
+}
+
+/// The objects stored for one collection, keyed by the `seq` they were
+/// uploaded with so they come back in attachment order.
+#[derive(Default)]
+pub struct Listing {
+    pub previews: BTreeMap<u64, String>,
+    pub originals: BTreeMap<u64, String>,
+}
+
+impl Listing {
+    fn insert(&mut self, prefix: &str, key: String) {
+        let Some(name) = key.strip_prefix(prefix) else {
+            return;
+        };
+        if let Some(original) = name.strip_prefix("original/") {
+            if let Some(seq) = parse_seq(original) {
+                self.originals.insert(seq, key);
+            }
+        } else if let Some(seq) = name.strip_suffix(".webp").and_then(|s| s.parse().ok()) {
+            self.previews.insert(seq, key);
+        }
+    }
+}
+
+fn parse_seq(file_name: &str) -> Option<u64> {
+    file_name.split_once('.')?.0.parse().ok()
+}
+
+#[instrument(skip(state))]
+pub async fn list_collection(state: &AppState, id: &str) -> Result<Listing, Error> {
+    let prefix = format!("{id}/");
+    let mut listing = Listing::default();
+    for key in list_keys(state, &prefix).await? {
+        listing.insert(&prefix, key);
+    }
+    Ok(listing)
+}
+
+async fn list_keys(state: &AppState, prefix: &str) -> Result<Vec<String>, Error> {
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let (page, _) = state
+            .bucket
+            .list_page(prefix.to_owned(), None, continuation_token, None, None)
+            .await?;
+        keys.extend(page.contents.into_iter().map(|object| object.key));
+        match page.next_continuation_token {
+            Some(token) if page.is_truncated => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+    Ok(keys)
+}
+
+/// Remove every object under `{id}/`, returning how many were deleted.
+/// Collections under legal hold are refused.
+#[instrument(skip(state))]
+pub async fn delete_collection(state: &AppState, id: &str) -> Result<usize, Error> {
+    if is_held(state, id).await? {
+        return Err(Error::LegalHold);
+    }
+    locked(state, id, remove_collection(state, id)).await
+}
+
+/// Only ever called with the collection's lock held, so that a concurrent
+/// upload can't write the manifest back.
+async fn remove_collection(state: &AppState, id: &str) -> Result<usize, Error> {
+    let keys = list_keys(state, &format!("{id}/")).await?;
+    for key in &keys {
+        state.bucket.delete_object(key).await?;
+    }
+    crate::retention::schedule(state, id, None).await?;
+    let _: () = state.redis.clone().del(seq_key(id)).await?;
+    Ok(keys.len())
+}
+
+/// Remove one image, its original and its manifest entry. Removing the last
+/// image removes the whole collection, unless it also holds a saved message.
+#[instrument(skip(state))]
+pub async fn delete_image(state: &AppState, id: &str, seq: u64) -> Result<(), Error> {
+    if is_held(state, id).await? {
+        return Err(Error::LegalHold);
+    }
+    locked(state, id, async {
+        let mut listing = list_collection(state, id).await?;
+        let preview = listing.previews.remove(&seq).ok_or(Error::NotFound)?;
+        state.bucket.delete_object(preview).await?;
+        if let Some(original) = listing.originals.remove(&seq) {
+            state.bucket.delete_object(original).await?;
+        }
+        let manifest = read_manifest(state, id).await?;
+        let is_avatar = |seq| manifest.as_ref().is_some_and(|m| m.is_avatar(seq));
+        if listing.previews.keys().all(|&seq| is_avatar(seq))
+            && manifest
+                .as_ref()
+                .is_none_or(|manifest| manifest.message.is_none())
+        {
+            remove_collection(state, id).await?;
+        } else if let Some(mut manifest) = manifest {
+            manifest.attachments.retain(|record| record.seq != seq);
+            write_manifest(state, id, &manifest).await?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_seq_reads_the_stem() {
+        assert_eq!(parse_seq("12.png"), Some(12));
+        assert_eq!(parse_seq("12"), None);
+        assert_eq!(parse_seq("manifest.json"), None);
+    }
+
+    #[test]
+    fn collection_id_accepts_links() {
+        assert_eq!(collection_id("abc123"), "abc123");
+        assert_eq!(collection_id("  abc123\n"), "abc123");
+        assert_eq!(collection_id("https://example.com/abc123"), "abc123");
+        assert_eq!(collection_id("https://example.com/abc123/"), "abc123");
+    }
+
+    #[test]
+    fn listing_orders_numerically() {
+        let mut listing = Listing::default();
+        for key in [
+            "c/10.webp",
+            "c/9.webp",
+            "c/manifest.json",
+            "c/original/9.jpg",
+            "d/1.webp",
+        ] {
+            listing.insert("c/", key.to_owned());
+        }
+        assert_eq!(
+            listing.previews.keys().copied().collect::<Vec<_>>(),
+            [9, 10]
+        );
+        assert_eq!(listing.originals.keys().copied().collect::<Vec<_>>(), [9]);
+    }
+
+    #[test]
+    fn jump_url_links_guild_messages() {
+        assert_eq!(
+            jump_url(Some(Id::new(1)), Id::new(2), Id::new(3)),
+            "https://discord.com/channels/1/2/3"
+        );
+    }
+
+    #[test]
+    fn jump_url_links_direct_messages() {
+        assert_eq!(
+            jump_url(None, Id::new(2), Id::new(3)),
+            "https://discord.com/channels/@me/2/3"
+        );
+    }
+}
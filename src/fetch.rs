@@ -0,0 +1,170 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::{Attempt, Policy},
+    Url,
+};
+
+use crate::Error;
+
+/// Redirects followed before giving up, as many as browsers allow.
+const MAX_REDIRECTS: usize = 10;
+
+/// Links come from anyone who can run `/save`, so the server must never be
+/// made to fetch something on its own network. Names are checked as they
+/// are resolved, so a name can't switch to a private address between a
+/// check and the connection.
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(Error::PrivateAddress.into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Addresses written into the URL are never resolved, so every hop is
+/// checked here as well.
+#[must_use]
+pub fn redirect_policy() -> Policy {
+    Policy::custom(|attempt: Attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if let Err(source) = check_url(attempt.url()) {
+            attempt.error(source)
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Only plain web links to hosts that aren't literal private addresses.
+pub fn check_url(url: &Url) -> Result<(), Error> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Error::UnsupportedScheme);
+    }
+    let host = url.host_str().ok_or(Error::UnsupportedScheme)?;
+    // IPv6 hosts keep their brackets.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let Ok(ip) = host.parse::<IpAddr>() else {
+        return Ok(());
+    };
+    if is_public(ip) {
+        Ok(())
+    } else {
+        Err(Error::PrivateAddress)
+    }
+}
+
+/// Whether `ip` is reachable on the public internet, rather than on a local,
+/// private or otherwise special-purpose network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", shared address space for carrier NAT, IETF
+        // protocol assignments, benchmarking and reserved.
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // IPv4 addresses can be written as IPv6 ones, and NAT64 translates them.
+    if let Some(mapped) = ip.to_ipv4_mapped() {
+        return is_public_v4(mapped);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., high, low] = segments;
+        return is_public_v4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)));
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, link local and documentation.
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_addresses_are_refused() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::10.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for ip in [
+            "1.1.1.1",
+            "162.159.128.233",
+            "2606:4700::6810:84e5",
+            "::ffff:1.1.1.1",
+            "64:ff9b::1.1.1.1",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn urls_need_a_web_scheme_and_public_host() {
+        let check = |url: &str| check_url(&Url::parse(url).unwrap());
+        assert!(check("https://example.com/a.png").is_ok());
+        assert!(check("http://1.1.1.1/a.png").is_ok());
+        assert!(matches!(
+            check("file:///etc/passwd"),
+            Err(Error::UnsupportedScheme)
+        ));
+        assert!(matches!(
+            check("http://127.0.0.1:6379/"),
+            Err(Error::PrivateAddress)
+        ));
+        assert!(matches!(check("http://[::1]/"), Err(Error::PrivateAddress)));
+    }
+}
@@ -3,19 +3,27 @@ use std::sync::Arc;
 use askama::Template;
 use axum::{
     body::Bytes,
-    extract::{Path, State},
-    http::HeaderMap,
-    Json,
+    extract::{multipart::MultipartError, Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Redirect,
+    Form, Json,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tower_sombrero::csp::CspNonce;
 use twilight_model::{
     http::interaction::InteractionResponse,
-    id::{marker::ApplicationMarker, Id},
+    id::{
+        marker::{ApplicationMarker, GuildMarker, UserMarker},
+        Id,
+    },
 };
 
 use crate::{
+    audit::{Action, Query as AuditQuery, Record},
+    auth::{list_sessions, revoke_sessions, Session, UserSessions},
+    collection::{collection_id, is_held, list_collection, read_manifest, Manifest},
     signature_validation::{SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    upload::{File, UploadLimits},
     AppState, Error, TemplateWrapper,
 };
 
@@ -23,17 +31,22 @@ use crate::{
 #[template(path = "index.hbs", escape = "html")]
 pub struct Index {
     root_url: Arc<str>,
+    session: Option<Session>,
     application_id: Id<ApplicationMarker>,
+    guild_install: bool,
     nonce: String,
 }
 
 pub async fn index(
     State(state): State<AppState>,
+    session: Session,
     CspNonce(nonce): CspNonce,
 ) -> TemplateWrapper<Index> {
     TemplateWrapper(Index {
         root_url: state.root_url,
+        session: Some(session),
         application_id: state.discord.application_id,
+        guild_install: state.guild_install,
         nonce,
     })
 }
@@ -42,45 +55,345 @@ pub async fn index(
 #[template(path = "view.hbs", escape = "html")]
 pub struct View {
     root_url: Arc<str>,
-    img_srcs: Vec<String>,
+    session: Option<Session>,
+    id: String,
+    images: Vec<ViewImage>,
+    manifest: Option<Manifest>,
+    /// Where to load the saved message's author avatar from, if it was kept.
+    author_avatar: Option<String>,
+    legal_hold: bool,
     application_id: Id<ApplicationMarker>,
+    guild_install: bool,
     nonce: String,
 }
 
+impl View {
+    /// Collections without a manifest have never been given a retention.
+    fn expires_at_string(&self) -> Option<String> {
+        self.manifest.as_ref()?.expires_at_string()
+    }
+}
+
+pub struct ViewImage {
+    seq: u64,
+    src: String,
+    original: Option<String>,
+}
+
 pub async fn view(
     State(state): State<AppState>,
+    session: Option<Session>,
     Path(id): Path<String>,
     CspNonce(nonce): CspNonce,
 ) -> Result<TemplateWrapper<View>, Error> {
-    let bucket_listing = state.bucket.list(format!("{id}/"), None).await?;
-    let mut img_srcs = Vec::new();
-    for listing in bucket_listing {
-        img_srcs.reserve(listing.contents.len());
-        for file in listing.contents {
-            let url = state.bucket.presign_get(file.key, 10, None).await?;
-            img_srcs.push(url);
+    let manifest = match read_manifest(&state, &id).await {
+        Ok(manifest) => {
+            // Without a session the instance is publicly readable, so there's no guild to check.
+            if let Some(session) = &session {
+                session.require_guild(manifest.as_ref().and_then(|m| m.guild_id))?;
+            }
+            manifest
         }
-    }
-    if img_srcs.is_empty() {
+        // The images are still worth showing if the manifest is damaged, but
+        // not knowing its guild, only to those who could see any guild's.
+        Err(Error::Json(source)) => {
+            error!(
+                ?source,
+                id, "Failed to parse manifest, showing collection without it"
+            );
+            session
+                .as_ref()
+                .ok_or(Error::Unauthorized)?
+                .require_every_guild(&state)?;
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    let mut listing = list_collection(&state, &id).await?;
+    let avatar = manifest.as_ref().and_then(|manifest| {
+        let seq = listing
+            .previews
+            .keys()
+            .copied()
+            .find(|&seq| manifest.is_avatar(seq))?;
+        listing.previews.remove(&seq)
+    });
+    let has_message = manifest.as_ref().is_some_and(|m| m.message.is_some());
+    if listing.previews.is_empty() && !has_message {
         return Err(Error::NotFound);
     }
+
+    let author_avatar = match avatar {
+        Some(key) => Some(state.bucket.presign_get(key, 10, None).await?),
+        None => None,
+    };
+    let mut images = Vec::with_capacity(listing.previews.len());
+    for (seq, key) in listing.previews {
+        let src = state.bucket.presign_get(key, 10, None).await?;
+        let original = match listing.originals.remove(&seq) {
+            Some(key) => Some(state.bucket.presign_get(key, 10, None).await?),
+            None => None,
+        };
+        images.push(ViewImage { seq, src, original });
+    }
+    let legal_hold = is_held(&state, &id).await?;
+
+    let actor = session.as_ref().map(|session| session.user.clone());
+    crate::audit::record(&state, &Record::new(actor, Action::View, &id)).await?;
     Ok(TemplateWrapper(View {
         root_url: state.root_url,
-        img_srcs,
+        session,
+        id,
+        images,
+        manifest,
+        author_avatar,
+        legal_hold,
         application_id: state.discord.application_id,
+        guild_install: state.guild_install,
         nonce,
     }))
 }
 
+/// Refuse changes to collections from guilds the session isn't authorized in.
+async fn require_collection_access(
+    state: &AppState,
+    session: &Session,
+    id: &str,
+) -> Result<(), Error> {
+    let manifest = read_manifest(state, id).await?;
+    session.require_guild(manifest.and_then(|m| m.guild_id))
+}
+
+#[derive(Deserialize)]
+pub struct Retention {
+    days: String,
+}
+
+pub async fn set_retention(
+    State(state): State<AppState>,
+    session: Session,
+    Path(id): Path<String>,
+    Form(form): Form<Retention>,
+) -> Result<Redirect, Error> {
+    require_collection_access(&state, &session, &id).await?;
+    let retention = match form.days.trim() {
+        "" | "never" => None,
+        days => {
+            let days: u16 = days.parse().map_err(|_| Error::InvalidRetention)?;
+            Some(time::Duration::days(days.into()))
+        }
+    };
+    crate::retention::set_retention(&state, &id, retention).await?;
+    Ok(Redirect::to(&format!("{}/{id}", state.root_url)))
+}
+
+#[derive(Deserialize)]
+pub struct LegalHold {
+    enabled: bool,
+}
+
+pub async fn set_legal_hold(
+    State(state): State<AppState>,
+    session: Session,
+    Path(id): Path<String>,
+    Form(form): Form<LegalHold>,
+) -> Result<Redirect, Error> {
+    require_collection_access(&state, &session, &id).await?;
+    crate::collection::set_legal_hold(&state, &id, form.enabled).await?;
+    let record = Record::new(Some(session.user), Action::legal_hold(form.enabled), &id);
+    crate::audit::record(&state, &record).await?;
+    Ok(Redirect::to(&format!("{}/{id}", state.root_url)))
+}
+
+pub async fn delete_collection(
+    State(state): State<AppState>,
+    session: Session,
+    Path(id): Path<String>,
+) -> Result<Redirect, Error> {
+    require_collection_access(&state, &session, &id).await?;
+    crate::collection::delete_collection(&state, &id).await?;
+    let record = Record::new(Some(session.user), Action::DeleteCollection, &id);
+    crate::audit::record_or_log(&state, &record).await;
+    Ok(Redirect::to(&format!("{}/", state.root_url)))
+}
+
+pub async fn delete_image(
+    State(state): State<AppState>,
+    session: Session,
+    Path((id, seq)): Path<(String, u64)>,
+) -> Result<Redirect, Error> {
+    require_collection_access(&state, &session, &id).await?;
+    crate::collection::delete_image(&state, &id, seq).await?;
+    let record = Record::new(Some(session.user), Action::DeleteImage, &id).seq(seq);
+    crate::audit::record_or_log(&state, &record).await;
+    Ok(Redirect::to(&format!("{}/{id}", state.root_url)))
+}
+
 #[derive(Serialize)]
 pub struct Upload {
     id: String,
+    uploaded: Vec<u64>,
+    failed: usize,
 }
 
-pub async fn upload(State(state): State<AppState>, body: Bytes) -> Result<Json<Upload>, Error> {
-    crate::upload::upload(state, body)
-        .await
-        .map(|id| Json(Upload { id }))
+/// The fields of a multipart upload. Every `file` field is an image; `guild`
+/// and `collection` are optional.
+#[derive(Default)]
+struct UploadForm {
+    files: Vec<File>,
+    guild: Option<Id<GuildMarker>>,
+    collection: Option<String>,
+}
+
+impl UploadForm {
+    async fn read(mut multipart: Multipart, limits: &UploadLimits) -> Result<Self, Error> {
+        let mut form = Self::default();
+        while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+            match field.name() {
+                Some("file") => {
+                    let name = field.file_name().map(ToOwned::to_owned);
+                    // Read in chunks so an oversized file is refused as soon as it's over.
+                    let mut data = Vec::new();
+                    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                        limits.check_size(data.len() + chunk.len())?;
+                        data.extend_from_slice(&chunk);
+                    }
+                    if !data.is_empty() {
+                        form.files.push(File {
+                            name,
+                            data: data.into(),
+                        });
+                    }
+                }
+                Some("guild") => {
+                    let guild = field.text().await.map_err(multipart_error)?;
+                    form.guild = Some(guild.trim().parse().map_err(|_| Error::InvalidGuild)?);
+                }
+                Some("collection") => {
+                    let collection = field.text().await.map_err(multipart_error)?;
+                    let id = collection_id(&collection);
+                    if !id.is_empty() {
+                        form.collection = Some(id.to_owned());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(form)
+    }
+}
+
+/// Going over the request body limit surfaces as a multipart error.
+fn multipart_error(source: MultipartError) -> Error {
+    if source.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        Error::TooLarge("upload")
+    } else {
+        Error::Multipart(source)
+    }
+}
+
+/// Uploads go to the collection named in the form, or otherwise a new one in
+/// the guild picked on the index page, or the session's only guild if it
+/// didn't offer a choice.
+pub async fn upload(
+    State(state): State<AppState>,
+    session: Session,
+    multipart: Multipart,
+) -> Result<Json<Upload>, Error> {
+    let form = UploadForm::read(multipart, &state.limits).await?;
+    if let Some(id) = form.collection {
+        return add_to_collection(&state, session, id, form.files).await;
+    }
+    let guild = form
+        .guild
+        .or_else(|| session.guilds.first().map(|guild| guild.id))
+        .ok_or(Error::NoPermissions)?;
+    session.require_guild(Some(guild))?;
+    let id = crate::randstring(16);
+    let manifest = Manifest::new(&state, Some(guild), Some(session.user.clone()));
+    store_files(&state, session, id, manifest, form.files).await
+}
+
+/// Add images to an existing collection, posted the same way as to `/upload`.
+pub async fn append(
+    State(state): State<AppState>,
+    session: Session,
+    Path(id): Path<String>,
+    multipart: Multipart,
+) -> Result<Json<Upload>, Error> {
+    let form = UploadForm::read(multipart, &state.limits).await?;
+    add_to_collection(&state, session, id, form.files).await
+}
+
+async fn add_to_collection(
+    state: &AppState,
+    session: Session,
+    id: String,
+    files: Vec<File>,
+) -> Result<Json<Upload>, Error> {
+    if !crate::collection::exists(state, &id).await? {
+        return Err(Error::NotFound);
+    }
+    require_collection_access(state, &session, &id).await?;
+    // Only stored if the collection has no manifest yet.
+    let manifest = Manifest::legacy(Some(session.user.clone()));
+    store_files(state, session, id, manifest, files).await
+}
+
+async fn store_files(
+    state: &AppState,
+    session: Session,
+    id: String,
+    manifest: Manifest,
+    files: Vec<File>,
+) -> Result<Json<Upload>, Error> {
+    let count = files.len();
+    let uploaded = crate::upload::add_files(state, &id, manifest, files).await?;
+    for &seq in &uploaded {
+        let record = Record::new(Some(session.user.clone()), Action::Upload, &id).seq(seq);
+        crate::audit::record_or_log(state, &record).await;
+    }
+    Ok(Json(Upload {
+        failed: count - uploaded.len(),
+        id,
+        uploaded,
+    }))
+}
+
+#[derive(Template)]
+#[template(path = "audit.hbs", escape = "html")]
+pub struct AuditLog {
+    root_url: Arc<str>,
+    session: Option<Session>,
+    query: AuditQuery,
+    records: Vec<Record>,
+    nonce: String,
+}
+
+pub async fn audit(
+    State(state): State<AppState>,
+    session: Session,
+    Query(query): Query<AuditQuery>,
+    CspNonce(nonce): CspNonce,
+) -> Result<TemplateWrapper<AuditLog>, Error> {
+    session.require_admin(&state)?;
+    let records = crate::audit::query(&state, &query).await?;
+    Ok(TemplateWrapper(AuditLog {
+        root_url: state.root_url,
+        session: Some(session),
+        query,
+        records,
+        nonce,
+    }))
+}
+
+pub async fn audit_json(
+    State(state): State<AppState>,
+    session: Session,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<Record>>, Error> {
+    session.require_admin(&state)?;
+    crate::audit::query(&state, &query).await.map(Json)
 }
 
 pub async fn interaction(
@@ -102,6 +415,50 @@ pub async fn interaction(
         &state.discord.verify_key,
     )?;
 
-    let response = crate::interact::interact(state, interaction).await;
+    let response = crate::interact::interact(state, interaction);
     Ok(Json(response))
 }
+
+#[derive(Template)]
+#[template(path = "sessions.hbs", escape = "html")]
+pub struct Sessions {
+    root_url: Arc<str>,
+    session: Option<Session>,
+    users: Vec<UserSessions>,
+    nonce: String,
+}
+
+pub async fn sessions(
+    State(state): State<AppState>,
+    session: Session,
+    CspNonce(nonce): CspNonce,
+) -> Result<TemplateWrapper<Sessions>, Error> {
+    session.require_admin(&state)?;
+    let users = list_sessions(&state).await?;
+    Ok(TemplateWrapper(Sessions {
+        root_url: state.root_url,
+        session: Some(session),
+        users,
+        nonce,
+    }))
+}
+
+pub async fn revoke_user_sessions(
+    State(state): State<AppState>,
+    session: Session,
+    Path(user): Path<Id<UserMarker>>,
+) -> Result<Redirect, Error> {
+    session.require_admin(&state)?;
+    revoke_sessions(&state, user, None).await?;
+    Ok(Redirect::to(&format!("{}/admin/sessions", state.root_url)))
+}
+
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    session: Session,
+    Path((user, id)): Path<(Id<UserMarker>, String)>,
+) -> Result<Redirect, Error> {
+    session.require_admin(&state)?;
+    revoke_sessions(&state, user, Some(&id)).await?;
+    Ok(Redirect::to(&format!("{}/admin/sessions", state.root_url)))
+}
@@ -1,25 +1,58 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
+use reqwest::Url;
 use tokio::task::JoinSet;
 use twilight_model::{
     application::{
-        command::CommandType,
-        interaction::{Interaction, InteractionContextType, InteractionData, InteractionType},
+        command::{Command, CommandType},
+        interaction::{
+            application_command::CommandOptionValue, Interaction, InteractionContextType,
+            InteractionData, InteractionMember, InteractionType,
+        },
+    },
+    channel::{
+        message::{
+            sticker::{MessageSticker, StickerFormatType},
+            Embed, MessageFlags,
+        },
+        Attachment,
     },
-    channel::message::MessageFlags,
     guild::Permissions,
     http::interaction::{InteractionResponse, InteractionResponseType},
+    id::{marker::GuildMarker, Id},
     oauth::ApplicationIntegrationType,
+    user::User,
+    util::ImageHash,
 };
 use twilight_util::builder::{
-    command::CommandBuilder,
+    command::{AttachmentBuilder, BooleanBuilder, CommandBuilder, StringBuilder},
     embed::{EmbedBuilder, EmbedFieldBuilder},
     InteractionResponseDataBuilder,
 };
 
-use crate::{randstring, upload::upload_raw, AppState, Error};
+use crate::{
+    audit::{Action, Record},
+    collection::{
+        allocate_seqs, append_to_manifest, collection_id, read_manifest, AttachmentRecord,
+        Manifest, Origin, Person, SourceMessage,
+    },
+    randstring,
+    snapshot::{default_avatar_url, MessageSnapshot, ProfileSnapshot},
+    upload::{upload_raw, Stored},
+    AppState, Error,
+};
 
 const UPLOAD_COMMAND_NAME: &str = "Save Attached Images";
+const PROFILE_COMMAND_NAME: &str = "Save Profile";
+const LEGAL_HOLD_COMMAND_NAME: &str = "legal-hold";
+const SAVE_COMMAND_NAME: &str = "save";
+/// How many files `/save` accepts, as `file1` through `file5`.
+const SAVE_FILE_OPTIONS: usize = 5;
+/// Largest size Discord's CDN serves avatars and banners at.
+const PROFILE_IMAGE_SIZE: u16 = 4096;
+const OTHER_GUILD: &str = "That collection belongs to another server.";
+const NOT_ALLOWED: &str = "There's no good way to enforce that only users who have permissions to use a user command can use it, so this has been disabled for now outside the configured servers.";
+const NOT_ALLOWED_IN_GUILD: &str = "You aren't allowed to save images in this server.";
 
 struct Response {
     description: String,
@@ -38,17 +71,19 @@ impl Response {
         self.fields.push((title, content));
     }
 
-    pub fn interaction_response(self) -> InteractionResponse {
+    pub fn embed(self) -> Embed {
         let mut embed = EmbedBuilder::new().description(self.description);
         for (title, content) in self.fields {
             let field = EmbedFieldBuilder::new(title, content).inline().build();
             embed = embed.field(field);
         }
-        let embed = embed.build();
+        embed.build()
+    }
 
+    pub fn interaction_response(self) -> InteractionResponse {
         let data = InteractionResponseDataBuilder::new()
             .flags(MessageFlags::EPHEMERAL)
-            .embeds([embed])
+            .embeds([self.embed()])
             .build();
 
         InteractionResponse {
@@ -58,30 +93,74 @@ impl Response {
     }
 }
 
-pub async fn interact(state: AppState, interaction: Interaction) -> InteractionResponse {
+pub fn interact(state: AppState, interaction: Interaction) -> InteractionResponse {
     match interaction.kind {
         InteractionType::Ping => InteractionResponse {
             kind: InteractionResponseType::Pong,
             data: None,
         },
-        InteractionType::ApplicationCommand => Box::pin(command(state, interaction))
-            .await
-            .interaction_response(),
+        InteractionType::ApplicationCommand => {
+            tokio::spawn(deferred_command(state, interaction));
+            deferred()
+        }
         _ => unsupported(),
     }
 }
 
+fn deferred() -> InteractionResponse {
+    let data = InteractionResponseDataBuilder::new()
+        .flags(MessageFlags::EPHEMERAL)
+        .build();
+    InteractionResponse {
+        kind: InteractionResponseType::DeferredChannelMessageWithSource,
+        data: Some(data),
+    }
+}
+
 fn unsupported() -> InteractionResponse {
     Response::new("Unsupported interaction kind".to_string()).interaction_response()
 }
 
+#[instrument(skip_all)]
+async fn deferred_command(state: AppState, interaction: Interaction) {
+    let token = interaction.token.clone();
+    let embed = Box::pin(command(state.clone(), interaction)).await.embed();
+    let update = state
+        .discord
+        .client
+        .interaction(state.discord.application_id)
+        .update_response(&token)
+        .embeds(Some(&[embed]))
+        .await;
+    if let Err(source) = update {
+        error!(?source, "Failed to edit deferred interaction response");
+    }
+}
+
 async fn command(state: AppState, interaction: Interaction) -> Response {
-    upload_attachments(state, interaction)
-        .await
-        .unwrap_or_else(|source| {
-            error!(?source, "Failed to process interaction");
-            Response::new(format!("Failed to process your request: {source}"))
-        })
+    let name = match &interaction.data {
+        Some(InteractionData::ApplicationCommand(data)) => data.name.clone(),
+        _ => String::new(),
+    };
+    let result = match name.as_str() {
+        LEGAL_HOLD_COMMAND_NAME => Box::pin(set_legal_hold(state, interaction)).await,
+        PROFILE_COMMAND_NAME => Box::pin(save_profile(state, interaction)).await,
+        SAVE_COMMAND_NAME => Box::pin(save_files(state, interaction)).await,
+        _ => Box::pin(upload_attachments(state, interaction)).await,
+    };
+    result.unwrap_or_else(|source| {
+        error!(?source, "Failed to process interaction");
+        Response::new(format!("Failed to process your request: {source}"))
+    })
+}
+
+/// What we learned by fetching an image, for records that Discord couldn't
+/// describe in advance.
+struct Downloaded {
+    seq: u64,
+    size: u64,
+    content_type: Option<String>,
+    stored: Stored,
 }
 
 #[instrument(skip(state))]
@@ -90,21 +169,398 @@ async fn upload_link(
     id: Arc<str>,
     upload_seq: u64,
     url: String,
-) -> Result<(), Error> {
-    let data = state.http.get(url).send().await?.bytes().await?;
-    upload_raw(state, id.as_ref(), upload_seq, data).await?;
-    Ok(())
+) -> Result<Downloaded, Error> {
+    let url = Url::parse(&url).map_err(|_| Error::UnsupportedScheme)?;
+    crate::fetch::check_url(&url)?;
+    let mut response = state.http.get(url).send().await?.error_for_status()?;
+    // Trust a declared length to refuse early, but count what actually
+    // arrives in case it's missing or wrong.
+    if let Some(length) = response.content_length() {
+        state
+            .limits
+            .check_size(usize::try_from(length).unwrap_or(usize::MAX))?;
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        state.limits.check_size(data.len() + chunk.len())?;
+        data.extend_from_slice(&chunk);
+    }
+    let size = data.len() as u64;
+    let content_type = image::guess_format(&data)
+        .ok()
+        .map(|f| f.to_mime_type().to_owned());
+    let stored = upload_raw(state, id.as_ref(), upload_seq, data.into()).await?;
+    Ok(Downloaded {
+        seq: upload_seq,
+        size,
+        content_type,
+        stored,
+    })
+}
+
+/// Images found in a message, each with the URL to fetch it from and the
+/// record it will be saved under.
+#[derive(Default)]
+struct FoundImages {
+    images: Vec<(String, AttachmentRecord)>,
+    skipped: usize,
+    seen: HashSet<String>,
+}
+
+impl FoundImages {
+    /// Sequence numbers follow the order images are added in. The same image is
+    /// only saved once, as embeds often repeat an attachment or each other,
+    /// whether by its own link or through Discord's proxy.
+    fn push(&mut self, download_url: String, mut record: AttachmentRecord) {
+        let keys: Vec<_> = std::iter::once(&download_url)
+            .chain(record.url.as_ref())
+            .map(|url| dedupe_key(url))
+            .collect();
+        if keys.iter().any(|key| self.seen.contains(key)) {
+            return;
+        }
+        self.seen.extend(keys);
+        record.seq = self.images.len() as u64;
+        self.images.push((download_url, record));
+    }
+
+    fn add_attachments(&mut self, attachments: &[Attachment]) {
+        for attachment in attachments {
+            let is_image = attachment
+                .content_type
+                .as_ref()
+                .is_some_and(|v| v.contains("image"));
+            if !is_image {
+                self.skipped += 1;
+                continue;
+            }
+            self.push(
+                attachment.url.clone(),
+                AttachmentRecord {
+                    seq: 0,
+                    filename: Some(attachment.filename.clone()),
+                    content_type: attachment.content_type.clone(),
+                    size: attachment.size,
+                    origin: Origin::Attachment,
+                    url: None,
+                    metadata: Vec::new(),
+                    sha256: None,
+                },
+            );
+        }
+    }
+
+    /// Linked images, Tenor and Giphy GIFs and link previews all show up as
+    /// embed images or thumbnails. For videos, the thumbnail is the poster frame.
+    fn add_embeds(&mut self, embeds: &[Embed]) {
+        for embed in embeds {
+            let image = embed
+                .image
+                .as_ref()
+                .map(|image| (&image.url, &image.proxy_url));
+            let thumbnail = embed
+                .thumbnail
+                .as_ref()
+                .map(|thumbnail| (&thumbnail.url, &thumbnail.proxy_url));
+            for (url, proxy_url) in image.into_iter().chain(thumbnail) {
+                // Discord's proxy keeps working when the original host blocks us.
+                let download_url = proxy_url.as_ref().unwrap_or(url).clone();
+                self.push(
+                    download_url,
+                    AttachmentRecord {
+                        seq: 0,
+                        filename: None,
+                        content_type: None,
+                        size: 0,
+                        origin: Origin::Embed,
+                        url: Some(url.clone()),
+                        metadata: Vec::new(),
+                        sha256: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Both the global and guild avatars and banners, as users can set each
+    /// separately. Users without an avatar get Discord's default one.
+    fn add_profile(&mut self, user: &User, member: Option<(Id<GuildMarker>, &InteractionMember)>) {
+        let mut images = vec![("Avatar", avatar_url(user))];
+        if let Some(hash) = user.banner {
+            images.push((
+                "Banner",
+                profile_image_url(&format!("banners/{}", user.id), hash),
+            ));
+        }
+        if let Some((guild, member)) = member {
+            let path = format!("guilds/{guild}/users/{}", user.id);
+            if let Some(hash) = member.avatar {
+                images.push((
+                    "Server avatar",
+                    profile_image_url(&format!("{path}/avatars"), hash),
+                ));
+            }
+            if let Some(hash) = member.banner {
+                images.push((
+                    "Server banner",
+                    profile_image_url(&format!("{path}/banners"), hash),
+                ));
+            }
+        }
+        for (name, url) in images {
+            self.push(
+                url,
+                AttachmentRecord {
+                    seq: 0,
+                    filename: Some(name.to_owned()),
+                    content_type: None,
+                    size: 0,
+                    origin: Origin::Profile,
+                    url: None,
+                    metadata: Vec::new(),
+                    sha256: None,
+                },
+            );
+        }
+    }
+
+    /// A message's author is shown with their avatar, which is kept with the
+    /// collection rather than loaded from Discord.
+    fn add_author(&mut self, author: &User) {
+        self.push(
+            avatar_url(author),
+            AttachmentRecord {
+                seq: 0,
+                filename: Some(author.name.clone()),
+                content_type: None,
+                size: 0,
+                origin: Origin::Avatar,
+                url: None,
+                metadata: Vec::new(),
+                sha256: None,
+            },
+        );
+    }
+
+    /// Lottie stickers are animations described in JSON rather than images,
+    /// so they are skipped.
+    fn add_stickers(&mut self, stickers: &[MessageSticker]) {
+        for sticker in stickers {
+            let extension = match sticker.format_type {
+                StickerFormatType::Png | StickerFormatType::Apng => "png",
+                StickerFormatType::Gif => "gif",
+                _ => {
+                    self.skipped += 1;
+                    continue;
+                }
+            };
+            self.push(
+                format!(
+                    "https://media.discordapp.net/stickers/{}.{extension}",
+                    sticker.id
+                ),
+                AttachmentRecord {
+                    seq: 0,
+                    filename: Some(sticker.name.clone()),
+                    content_type: None,
+                    size: 0,
+                    origin: Origin::Sticker,
+                    url: None,
+                    metadata: Vec::new(),
+                    sha256: None,
+                },
+            );
+        }
+    }
+}
+
+/// Whether the command came through the bot installed in a guild, rather than
+/// through an app a moderator installed on their own account.
+const fn is_guild_installed(interaction: &Interaction) -> bool {
+    interaction.authorizing_integration_owners.guild.is_some()
+}
+
+/// Guild-installed commands are already hidden from members without the
+/// required permissions, so refusing one only happens when a server admin has
+/// overridden that, or the policy denies the member by role or ID.
+fn not_allowed(interaction: &Interaction) -> Response {
+    let message = if is_guild_installed(interaction) {
+        NOT_ALLOWED_IN_GUILD
+    } else {
+        NOT_ALLOWED
+    };
+    Response::new(message.to_string())
+}
+
+/// Discord serves attachments from either host, with signing parameters in
+/// the query that change every time a link is handed out.
+const DISCORD_MEDIA_HOSTS: [&str; 2] = ["cdn.discordapp.com", "media.discordapp.net"];
+
+/// What two links to the same image have in common. Other hosts' queries
+/// may pick the image, so they are kept.
+fn dedupe_key(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_owned();
+    };
+    parsed.set_fragment(None);
+    if parsed
+        .host_str()
+        .is_some_and(|host| DISCORD_MEDIA_HOSTS.contains(&host))
+    {
+        return format!("discord:{}", parsed.path());
+    }
+    parsed.into()
+}
+
+/// Users without an avatar get one of Discord's defaults.
+fn avatar_url(user: &User) -> String {
+    user.avatar.map_or_else(
+        || default_avatar_url(user.id),
+        |hash| profile_image_url(&format!("avatars/{}", user.id), hash),
+    )
+}
+
+/// Animated avatars and banners are kept animated.
+fn profile_image_url(path: &str, hash: ImageHash) -> String {
+    let extension = if hash.is_animated() { "gif" } else { "png" };
+    format!("https://cdn.discordapp.com/{path}/{hash}.{extension}?size={PROFILE_IMAGE_SIZE}")
+}
+
+/// Fetch and store every found image at once, filling in what each download
+/// tells us about it. Images go after anything the collection already holds.
+/// Returns how many were uploaded and how many failed.
+async fn upload_found(
+    state: &AppState,
+    id: &Arc<str>,
+    manifest: &mut Manifest,
+    images: Vec<(String, AttachmentRecord)>,
+) -> Result<(usize, usize), Error> {
+    let seqs = allocate_seqs(state, id, images.len() as u64).await?;
+    let mut set = JoinSet::new();
+    let mut pending = HashSet::new();
+    for (seq, (url, mut record)) in seqs.zip(images) {
+        record.seq = seq;
+        set.spawn(upload_link(state.clone(), id.clone(), seq, url));
+        pending.insert(seq);
+        manifest.attachments.push(record);
+    }
+
+    let mut failures = 0;
+    let mut uploaded = 0;
+
+    while let Some(res) = set.join_next().await {
+        match res {
+            Ok(Ok(downloaded)) => {
+                uploaded += 1;
+                pending.remove(&downloaded.seq);
+                let record = manifest
+                    .attachments
+                    .iter_mut()
+                    .find(|record| record.seq == downloaded.seq);
+                if let Some(record) = record {
+                    record.size = downloaded.size;
+                    record.metadata = downloaded.stored.metadata;
+                    record.sha256 = Some(downloaded.stored.sha256);
+                    if record.content_type.is_none() {
+                        record.content_type = downloaded.content_type;
+                    }
+                }
+            }
+            Err(source) => {
+                failures += 1;
+                error!(?source, "S3 uploader panicked");
+            }
+            Ok(Err(source)) => {
+                failures += 1;
+                error!(?source, "S3 uploader failed");
+            }
+        }
+    }
+    // Whatever didn't finish was never stored, so it has no place in the manifest.
+    manifest
+        .attachments
+        .retain(|record| !pending.contains(&record.seq));
+    Ok((uploaded, failures))
+}
+
+/// Store the manifest, or add to the one already there, and record who saved
+/// it. Returns the collection's link.
+async fn save_collection(
+    state: &AppState,
+    id: &str,
+    manifest: Manifest,
+    saved_by: Option<Person>,
+) -> Result<String, Error> {
+    append_to_manifest(state, id, manifest).await?;
+    let record = Record::new(saved_by, Action::Upload, id);
+    crate::audit::record_or_log(state, &record).await;
+    Ok(format!("<{}/{id}/>", state.root_url))
+}
+
+fn is_authorized(state: &AppState, interaction: &Interaction) -> bool {
+    let (Some(user), Some(member)) = (interaction.author_id(), interaction.member.as_ref()) else {
+        return false;
+    };
+    interaction
+        .guild_id
+        .is_some_and(|g| state.guilds.contains(&g))
+        && state.policy.allows(
+            user,
+            &member.roles,
+            member.permissions.unwrap_or_else(Permissions::empty),
+        )
 }
 
 #[instrument(skip(state))]
-async fn upload_attachments(state: AppState, interaction: Interaction) -> Result<Response, Error> {
-    if interaction.guild_id.is_none_or(|g| g != state.guild)
-        || !interaction.member.is_some_and(|m| {
-            m.permissions
-                .is_some_and(|p| p.contains(Permissions::MODERATE_MEMBERS))
-        })
+async fn set_legal_hold(state: AppState, interaction: Interaction) -> Result<Response, Error> {
+    if !is_authorized(&state, &interaction) {
+        return Ok(not_allowed(&interaction));
+    }
+    let actor = interaction.author().map(|user| Person {
+        id: user.id,
+        name: user.name.clone(),
+    });
+    let Some(InteractionData::ApplicationCommand(data)) = interaction.data else {
+        return Err(Error::MissingCommandData);
+    };
+    let mut collection = None;
+    let mut enabled = true;
+    for option in data.options {
+        match (option.name.as_str(), option.value) {
+            ("collection", CommandOptionValue::String(value)) => collection = Some(value),
+            ("enabled", CommandOptionValue::Boolean(value)) => enabled = value,
+            _ => {}
+        }
+    }
+    let collection = collection.ok_or(Error::MissingTarget)?;
+    let id = collection_id(&collection);
+    let manifest = read_manifest(&state, id).await?;
+    if manifest
+        .and_then(|manifest| manifest.guild_id)
+        .is_some_and(|guild| Some(guild) != interaction.guild_id)
     {
-        return Ok(Response::new("There's no good way to enforce that only users who have permissions to use a user command can use it, so this has been disabled for now outside the main server.".to_string()));
+        return Ok(Response::new(OTHER_GUILD.to_string()));
+    }
+
+    crate::collection::set_legal_hold(&state, id, enabled).await?;
+    let record = Record::new(actor, Action::legal_hold(enabled), id);
+    crate::audit::record(&state, &record).await?;
+    let verb = if enabled { "Placed" } else { "Released" };
+    let preposition = if enabled { "on" } else { "from" };
+    Ok(Response::new(format!(
+        "{verb} <{}/{id}/> {preposition} legal hold",
+        state.root_url
+    )))
+}
+
+#[instrument(skip(state))]
+async fn upload_attachments(state: AppState, interaction: Interaction) -> Result<Response, Error> {
+    let saved_by = interaction.author().map(|user| Person {
+        id: user.id,
+        name: user.name.clone(),
+    });
+    if !is_authorized(&state, &interaction) {
+        return Ok(not_allowed(&interaction));
     }
     let Some(InteractionData::ApplicationCommand(data)) = interaction.data else {
         return Err(Error::MissingCommandData);
@@ -116,83 +572,402 @@ async fn upload_attachments(state: AppState, interaction: Interaction) -> Result
         .get(&target.cast())
         .ok_or(Error::MessageNotFound)?;
 
-    let mut set = JoinSet::new();
-
     let upload_id: Arc<str> = randstring(16).into();
-    let mut skipped_ctype = 0;
 
-    for (upload_seq, attachment) in message
-        .attachments
-        .iter()
-        .filter(|v| {
-            let is_image = v.content_type.as_ref().is_some_and(|v| v.contains("image"));
-            if !is_image {
-                skipped_ctype += 1;
-            }
-            is_image
-        })
-        .enumerate()
-    {
-        let state = state.clone();
-        let id = upload_id.clone();
-        set.spawn(upload_link(
-            state,
-            id,
-            upload_seq as u64,
-            attachment.url.clone(),
-        ));
+    let mut manifest = Manifest::new(&state, interaction.guild_id, saved_by.clone());
+    manifest.source = Some(SourceMessage {
+        guild_id: interaction.guild_id,
+        channel_id: message.channel_id,
+        message_id: message.id,
+        author: Person {
+            id: message.author.id,
+            name: message.author.name.clone(),
+        },
+    });
+
+    let snapshot = MessageSnapshot::from_message(message);
+    let has_text = !snapshot.is_empty();
+    manifest.message = Some(snapshot);
+
+    let mut found = FoundImages::default();
+    found.add_attachments(&message.attachments);
+    found.add_embeds(&message.embeds);
+    found.add_stickers(&message.sticker_items);
+    for forwarded in &message.message_snapshots {
+        found.add_attachments(&forwarded.message.attachments);
+        found.add_embeds(&forwarded.message.embeds);
+        found.add_stickers(&forwarded.message.sticker_items);
+    }
+    // Only worth keeping if the message is.
+    if !found.images.is_empty() || has_text {
+        found.add_author(&message.author);
     }
+    let with_avatar = found
+        .images
+        .iter()
+        .any(|(_, record)| record.origin == Origin::Avatar);
 
-    let mut failures = 0;
-    let mut uploaded = 0;
+    let (mut uploaded, mut failures) =
+        upload_found(&state, &upload_id, &mut manifest, found.images).await?;
+    // The author's avatar isn't one of the message's images, so leave it out of the counts.
+    if with_avatar {
+        let stored = manifest
+            .attachments
+            .iter()
+            .any(|record| record.origin == Origin::Avatar);
+        if stored {
+            uploaded -= 1;
+        } else {
+            failures -= 1;
+        }
+    }
 
-    while let Some(res) = set.join_next().await {
-        if matches!(res, Ok(Ok(()))) {
-            uploaded += 1;
+    let content = if uploaded == 0 && !has_text {
+        "Found no images or text".to_string()
+    } else {
+        let link = save_collection(&state, &upload_id, manifest, saved_by).await?;
+        if uploaded == 0 {
+            format!("Saved the message text: {link}")
         } else {
-            failures += 1;
+            format!("Uploaded {uploaded} images: {link}")
         }
-        match res {
-            Err(source) => error!(?source, "S3 uploader panicked"),
-            Ok(Err(source)) => error!(?source, "S3 uploader failed"),
-            Ok(Ok(())) => {}
+    };
+
+    let mut response = Response::new(content);
+    if found.skipped != 0 {
+        response.add_field("Skipped".to_string(), found.skipped.to_string());
+    }
+    if failures != 0 {
+        response.add_field("Failed".to_string(), failures.to_string());
+    }
+
+    Ok(response)
+}
+
+#[instrument(skip(state))]
+async fn save_profile(state: AppState, interaction: Interaction) -> Result<Response, Error> {
+    let saved_by = interaction.author().map(|user| Person {
+        id: user.id,
+        name: user.name.clone(),
+    });
+    if !is_authorized(&state, &interaction) {
+        return Ok(not_allowed(&interaction));
+    }
+    let Some(InteractionData::ApplicationCommand(data)) = interaction.data else {
+        return Err(Error::MissingCommandData);
+    };
+    let target = data.target_id.ok_or(Error::MissingTarget)?.cast();
+    let resolved = data.resolved.ok_or(Error::NoResolvedData)?;
+    let resolved_user = resolved.users.get(&target).ok_or(Error::UserNotFound)?;
+    // Interaction data leaves banners out, so ask for the full user.
+    let user = match state.discord.client.user(target).await {
+        Ok(response) => response.model().await?,
+        Err(source) => {
+            warn!(?source, "Failed to fetch user, saving without their banner");
+            resolved_user.clone()
+        }
+    };
+    let member = interaction.guild_id.zip(resolved.members.get(&target));
+
+    let upload_id: Arc<str> = randstring(16).into();
+    let mut manifest = Manifest::new(&state, interaction.guild_id, saved_by.clone());
+    manifest.profile = Some(ProfileSnapshot::new(
+        &user,
+        member.and_then(|(_, member)| member.nick.clone()),
+    ));
+
+    let mut found = FoundImages::default();
+    found.add_profile(&user, member);
+    let (uploaded, failures) =
+        upload_found(&state, &upload_id, &mut manifest, found.images).await?;
+
+    let content = if uploaded == 0 {
+        "Failed to save any profile images".to_string()
+    } else {
+        let link = save_collection(&state, &upload_id, manifest, saved_by).await?;
+        format!("Saved {}'s profile: {link}", user.name)
+    };
+
+    let mut response = Response::new(content);
+    if failures != 0 {
+        response.add_field("Failed".to_string(), failures.to_string());
+    }
+    Ok(response)
+}
+
+#[instrument(skip(state))]
+async fn save_files(state: AppState, interaction: Interaction) -> Result<Response, Error> {
+    let saved_by = interaction.author().map(|user| Person {
+        id: user.id,
+        name: user.name.clone(),
+    });
+    if !is_authorized(&state, &interaction) {
+        return Ok(not_allowed(&interaction));
+    }
+    let Some(InteractionData::ApplicationCommand(data)) = interaction.data else {
+        return Err(Error::MissingCommandData);
+    };
+    let mut attachments = Vec::new();
+    let mut link = None;
+    let mut collection = None;
+    for option in data.options {
+        match (option.name.as_str(), option.value) {
+            (_, CommandOptionValue::Attachment(id)) => {
+                let resolved = data.resolved.as_ref().ok_or(Error::NoResolvedData)?;
+                let attachment = resolved.attachments.get(&id).ok_or(Error::MissingTarget)?;
+                attachments.push(attachment.clone());
+            }
+            ("url", CommandOptionValue::String(value)) => link = Some(value),
+            ("collection", CommandOptionValue::String(value)) => collection = Some(value),
+            _ => {}
         }
     }
+    let target = match collection {
+        Some(input) => {
+            let id = collection_id(&input).to_owned();
+            if !crate::collection::exists(&state, &id).await? {
+                return Ok(Response::new(format!("There's no collection `{id}`")));
+            }
+            let manifest = read_manifest(&state, &id).await?;
+            if manifest
+                .and_then(|manifest| manifest.guild_id)
+                .is_some_and(|guild| Some(guild) != interaction.guild_id)
+            {
+                return Ok(Response::new(OTHER_GUILD.to_string()));
+            }
+            Some(id)
+        }
+        None => None,
+    };
+
+    let mut found = FoundImages::default();
+    found.add_attachments(&attachments);
+    if let Some(link) = link {
+        let Some(url) = Url::parse(link.trim()).ok() else {
+            return Ok(Response::new(format!("`{link}` isn't a URL")));
+        };
+        if let Err(source) = crate::fetch::check_url(&url) {
+            return Ok(Response::new(format!("Can't save `{link}`: {source}")));
+        }
+        found.push(
+            url.to_string(),
+            AttachmentRecord {
+                seq: 0,
+                filename: None,
+                content_type: None,
+                size: 0,
+                origin: Origin::Link,
+                url: Some(url.to_string()),
+                metadata: Vec::new(),
+                sha256: None,
+            },
+        );
+    }
+    if found.images.is_empty() && found.skipped == 0 {
+        return Ok(Response::new(
+            "Attach a file or give a URL to save".to_string(),
+        ));
+    }
+
+    let appending = target.is_some();
+    let upload_id: Arc<str> = target.unwrap_or_else(|| randstring(16)).into();
+    // Only stored as is if the collection has no manifest yet, otherwise its
+    // records are added to the existing one.
+    let mut manifest = if appending {
+        Manifest::legacy(saved_by.clone())
+    } else {
+        Manifest::new(&state, interaction.guild_id, saved_by.clone())
+    };
+    let (uploaded, failures) =
+        upload_found(&state, &upload_id, &mut manifest, found.images).await?;
 
     let content = if uploaded == 0 {
-        "Found no attachments".to_string()
+        "Found no images".to_string()
     } else {
-        format!(
-            "Uploaded {uploaded} attachments: <{}/{upload_id}/>",
-            state.root_url
-        )
+        let link = save_collection(&state, &upload_id, manifest, saved_by).await?;
+        if appending {
+            format!("Added {uploaded} images to {link}")
+        } else {
+            format!("Uploaded {uploaded} images: {link}")
+        }
     };
 
     let mut response = Response::new(content);
-    if skipped_ctype != 0 {
-        response.add_field("Skipped".to_string(), skipped_ctype.to_string());
+    if found.skipped != 0 {
+        response.add_field("Skipped".to_string(), found.skipped.to_string());
     }
     if failures != 0 {
         response.add_field("Failed".to_string(), failures.to_string());
     }
-
     Ok(response)
 }
 
+/// In guild-install mode, commands are registered in each configured guild and
+/// Discord hides them from members without the policy's permissions. Otherwise
+/// they are global user-install commands, checked only once they arrive.
+/// Install types and contexts only apply to global commands. Guild commands
+/// are always installed to and used in their guild.
+fn install(command: CommandBuilder, state: &AppState) -> Command {
+    if !state.guild_install {
+        return command
+            .integration_types([ApplicationIntegrationType::UserInstall])
+            .contexts([
+                InteractionContextType::Guild,
+                InteractionContextType::PrivateChannel,
+            ])
+            .build();
+    }
+    let policy = &state.policy;
+    // Discord reads no permissions as administrators only, and members the
+    // policy lets in by role or user may lack the permissions, so either would
+    // hide the command from people allowed to use it.
+    if policy.permissions.is_empty()
+        || !policy.allowed_roles.is_empty()
+        || !policy.allowed_users.is_empty()
+    {
+        command.build()
+    } else {
+        command
+            .default_member_permissions(policy.permissions)
+            .build()
+    }
+}
+
 #[instrument(skip_all)]
 pub async fn register_commands(state: &AppState) -> Result<(), Error> {
-    let upload_command = CommandBuilder::new(UPLOAD_COMMAND_NAME, "", CommandType::Message)
-        .integration_types([ApplicationIntegrationType::UserInstall])
-        .contexts([
-            InteractionContextType::Guild,
-            InteractionContextType::PrivateChannel,
-        ])
-        .build();
-    state
+    let upload_command = install(
+        CommandBuilder::new(UPLOAD_COMMAND_NAME, "", CommandType::Message),
+        state,
+    );
+    let profile_command = install(
+        CommandBuilder::new(PROFILE_COMMAND_NAME, "", CommandType::User),
+        state,
+    );
+    let legal_hold_command = install(
+        CommandBuilder::new(
+            LEGAL_HOLD_COMMAND_NAME,
+            "Keep a collection from being deleted",
+            CommandType::ChatInput,
+        )
+        .option(StringBuilder::new("collection", "Collection ID or link").required(true))
+        .option(BooleanBuilder::new(
+            "enabled",
+            "Whether the hold is in place (default: true)",
+        )),
+        state,
+    );
+    let mut save_command = CommandBuilder::new(
+        SAVE_COMMAND_NAME,
+        "Save images from files or a link",
+        CommandType::ChatInput,
+    );
+    for number in 1..=SAVE_FILE_OPTIONS {
+        save_command = save_command.option(AttachmentBuilder::new(
+            format!("file{number}"),
+            "An image to save",
+        ));
+    }
+    let save_command = install(
+        save_command
+            .option(StringBuilder::new("url", "Link to an image to save"))
+            .option(StringBuilder::new(
+                "collection",
+                "Collection ID or link to add the images to, instead of starting a new one",
+            )),
+        state,
+    );
+    let commands = [
+        upload_command,
+        profile_command,
+        save_command,
+        legal_hold_command,
+    ];
+    let client = state
         .discord
         .client
-        .interaction(state.discord.application_id)
-        .set_global_commands(&[upload_command])
-        .await?;
+        .interaction(state.discord.application_id);
+    if state.guild_install {
+        for guild in state.guilds.iter() {
+            client.set_guild_commands(*guild, &commands).await?;
+        }
+        // Drop the user-install commands, so they don't show up twice.
+        client.set_global_commands(&[]).await?;
+    } else {
+        client.set_global_commands(&commands).await?;
+        // Switching back from guild-install mode, so clean up after it. The bot
+        // may not have been invited with commands, so this isn't fatal.
+        for guild in state.guilds.iter() {
+            if let Err(source) = client.set_guild_commands(*guild, &[]).await {
+                warn!(?source, %guild, "Failed to clear guild commands");
+            }
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(url: Option<&str>) -> AttachmentRecord {
+        AttachmentRecord {
+            seq: 0,
+            filename: None,
+            content_type: None,
+            size: 0,
+            origin: Origin::Embed,
+            url: url.map(str::to_owned),
+            metadata: Vec::new(),
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn attachment_links_match_across_hosts_and_signatures() {
+        assert_eq!(
+            dedupe_key("https://cdn.discordapp.com/attachments/1/2/a.png?ex=1&is=2&hm=3"),
+            dedupe_key("https://media.discordapp.net/attachments/1/2/a.png?ex=4&is=5&hm=6&"),
+        );
+    }
+
+    #[test]
+    fn other_hosts_keep_their_query() {
+        assert_ne!(
+            dedupe_key("https://example.com/image?id=1"),
+            dedupe_key("https://example.com/image?id=2"),
+        );
+        assert_eq!(
+            dedupe_key("https://example.com/image?id=1#top"),
+            dedupe_key("https://example.com/image?id=1"),
+        );
+    }
+
+    #[test]
+    fn embeds_of_an_attachment_are_saved_once() {
+        let mut found = FoundImages::default();
+        found.push(
+            "https://cdn.discordapp.com/attachments/1/2/a.png?ex=1".to_owned(),
+            record(None),
+        );
+        found.push(
+            "https://media.discordapp.net/attachments/1/2/a.png?ex=2".to_owned(),
+            record(Some("https://cdn.discordapp.com/attachments/1/2/a.png")),
+        );
+        assert_eq!(found.images.len(), 1);
+    }
+
+    #[test]
+    fn embeds_match_by_original_link() {
+        let mut found = FoundImages::default();
+        found.push(
+            "https://images-ext-1.discordapp.net/external/a/https/example.com/a.png".to_owned(),
+            record(Some("https://example.com/a.png")),
+        );
+        found.push(
+            "https://images-ext-2.discordapp.net/external/b/https/example.com/a.png".to_owned(),
+            record(Some("https://example.com/a.png")),
+        );
+        assert_eq!(found.images.len(), 1);
+        assert_eq!(found.images[0].1.seq, 0);
+    }
+}
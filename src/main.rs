@@ -4,7 +4,7 @@ use std::{net::SocketAddr, sync::Arc};
 use askama::Template;
 use axum::{
     body::Body,
-    extract::{Request, State},
+    extract::{DefaultBodyLimit, Request, State},
     http::StatusCode,
     middleware::Next,
     response::{Html, IntoResponse, Response},
@@ -16,6 +16,7 @@ use oauth2::{
     basic::BasicErrorResponseType, HttpClientError, RequestTokenError, StandardErrorResponse,
 };
 use rand::{distr::Alphanumeric, Rng};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tokio::net::TcpListener;
 use tower_http::{compression::CompressionLayer, services::ServeDir};
 use tower_sombrero::{
@@ -27,10 +28,17 @@ use tracing::Level;
 
 pub use crate::state::AppState;
 
+mod audit;
 mod auth;
+mod collection;
+mod fetch;
 mod handler;
 mod interact;
+mod policy;
+mod retention;
+mod sanitize;
 mod signature_validation;
+mod snapshot;
 mod state;
 mod upload;
 
@@ -49,6 +57,8 @@ async fn main() {
         .await
         .expect("Failed to register commands");
 
+    tokio::spawn(retention::sweeper(state.clone()));
+
     let app = router(state);
 
     let bind_address = SocketAddr::from(([0, 0, 0, 0], 8080));
@@ -77,12 +87,35 @@ pub fn router(state: AppState) -> Router {
         ])
         .style_src(CspSource::Nonce)
         .base_uri(CspSource::None)
-        .img_src([CspSource::Host(state.bucket.url()), CspSource::SelfOrigin]);
+        .img_src([
+            CspSource::Host(state.bucket.url()),
+            CspSource::Host("https://cdn.discordapp.com".to_string()),
+            CspSource::SelfOrigin,
+        ]);
     let sombrero = Sombrero::default().content_security_policy(csp);
 
+    // Uploads can carry several images at once, so they get more room than
+    // axum's default request body limit.
+    let upload_limit = DefaultBodyLimit::max(state.limits.request_bytes);
     let router = Router::new()
         .route("/", get(handler::index))
-        .route("/upload", post(handler::upload));
+        .route("/upload", post(handler::upload).layer(upload_limit))
+        .route("/{id}/upload", post(handler::append).layer(upload_limit))
+        .route("/{id}/retention", post(handler::set_retention))
+        .route("/{id}/hold", post(handler::set_legal_hold))
+        .route("/{id}/delete", post(handler::delete_collection))
+        .route("/{id}/{seq}/delete", post(handler::delete_image))
+        .route("/admin/audit", get(handler::audit))
+        .route("/admin/audit.json", get(handler::audit_json))
+        .route("/admin/sessions", get(handler::sessions))
+        .route(
+            "/admin/sessions/{user}/revoke",
+            post(handler::revoke_user_sessions),
+        )
+        .route(
+            "/admin/sessions/{user}/{session}/revoke",
+            post(handler::revoke_session),
+        );
     let auth = axum::middleware::from_fn_with_state(state.clone(), auth::middleware);
 
     let router = if std::env::var("PUBLICLY_READABLE")
@@ -100,6 +133,7 @@ pub fn router(state: AppState) -> Router {
 
     router
         .route("/oauth2/callback", get(auth::authenticate))
+        .route("/logout", post(auth::logout))
         .route("/interactions", post(handler::interaction))
         .nest_service("/assets", serve_dir)
         .layer(CompressionLayer::new())
@@ -150,6 +184,8 @@ pub enum Error {
     OAuth2Url(#[from] oauth2::url::ParseError),
     #[error("Join error")]
     Join(#[from] tokio::task::JoinError),
+    #[error("Image conversion is unavailable")]
+    Conversions(#[from] tokio::sync::AcquireError),
     #[error("OAuth2 Code Exchange failed")]
     CodeExchangeFailed(#[from] CodeExchangeFailure),
     #[error("Missing required header with name {0}")]
@@ -158,6 +194,20 @@ pub enum Error {
     WebPStr(String),
     #[error("Failed to extract secure interaction")]
     InvalidSignature(#[from] signature_validation::ExtractFailure),
+    #[error("The guild must be a Discord ID")]
+    InvalidGuild,
+    #[error("Only http(s) links can be saved")]
+    UnsupportedScheme,
+    #[error("Links to private or local addresses can't be saved")]
+    PrivateAddress,
+    #[error("Retention must be a whole number of days")]
+    InvalidRetention,
+    #[error("That retention has already run out, so the collection would be deleted right away")]
+    RetentionElapsed,
+    #[error("This collection is busy, try again in a moment")]
+    CollectionBusy,
+    #[error("This collection is under legal hold and can't be deleted")]
+    LegalHold,
     #[error("Invalid OAuth2 State")]
     InvalidState,
     #[error("You do not have the required role to access this application")]
@@ -174,12 +224,21 @@ pub enum Error {
     NoResolvedData,
     #[error("Message not sent in resolved data")]
     MessageNotFound,
+    #[error("User not sent in resolved data")]
+    UserNotFound,
+    #[error("Malformed upload: {0}")]
+    Multipart(axum::extract::multipart::MultipartError),
+    #[error("No files were uploaded")]
+    NoFiles,
+    #[error("Image is too large: the {0} is over this instance's limit")]
+    TooLarge(&'static str),
 }
 
 #[derive(Template)]
 #[template(path = "error.hbs", escape = "html")]
 struct ErrorTemplate {
     root_url: Arc<str>,
+    session: Option<auth::Session>,
     error: Arc<Error>,
     nonce: String,
 }
@@ -209,20 +268,32 @@ impl Error {
             | Self::Askama(_)
             | Self::Json(_)
             | Self::Join(_)
+            | Self::Conversions(_)
             | Self::OAuth2Url(_)
             | Self::OAuth2RequestToken(_)
             | Self::WebPStr(_)
             | Self::MissingCommandData
             | Self::MissingTarget
             | Self::NoResolvedData
-            | Self::MessageNotFound => StatusCode::INTERNAL_SERVER_ERROR,
+            | Self::MessageNotFound
+            | Self::UserNotFound => StatusCode::INTERNAL_SERVER_ERROR,
             Self::InvalidState
+            | Self::InvalidRetention
+            | Self::InvalidGuild
+            | Self::UnsupportedScheme
+            | Self::PrivateAddress
+            | Self::RetentionElapsed
             | Self::CodeExchangeFailed(_)
             | Self::Image(_)
-            | Self::MissingHeader(_) => StatusCode::BAD_REQUEST,
+            | Self::MissingHeader(_)
+            | Self::Multipart(_)
+            | Self::NoFiles => StatusCode::BAD_REQUEST,
             Self::NoPermissions => StatusCode::FORBIDDEN,
             Self::Unauthorized | Self::InvalidSignature(_) => StatusCode::UNAUTHORIZED,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::LegalHold => StatusCode::CONFLICT,
+            Self::CollectionBusy => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 }
@@ -251,6 +322,7 @@ async fn error_middleware(State(state): State<AppState>, mut req: Request, next:
         let status = error.status();
         let error = TemplateWrapper(ErrorTemplate {
             root_url: state.root_url,
+            session: None,
             error,
             nonce,
         });
@@ -267,3 +339,9 @@ pub fn randstring(len: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+#[must_use]
+pub fn format_time(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339)
+        .unwrap_or_else(|_| time.unix_timestamp().to_string())
+}
@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+
+use twilight_model::{
+    guild::Permissions,
+    id::{
+        marker::{RoleMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Who may use the web interface and the Discord commands. Both paths consult
+/// the same policy, so a user can never do in one what they can't in the other.
+///
+/// Rules are checked in order, and the first that matches decides:
+/// 1. users in `denied_users` are refused
+/// 2. users in `allowed_users` are let in
+/// 3. members with any role in `denied_roles` are refused
+/// 4. members with any role in `allowed_roles` are let in
+/// 5. members with all of `permissions` are let in, unless it is empty
+#[derive(Debug, Clone)]
+pub struct AccessPolicy {
+    pub permissions: Permissions,
+    pub allowed_roles: HashSet<Id<RoleMarker>>,
+    pub denied_roles: HashSet<Id<RoleMarker>>,
+    pub allowed_users: HashSet<Id<UserMarker>>,
+    pub denied_users: HashSet<Id<UserMarker>>,
+}
+
+impl AccessPolicy {
+    #[must_use]
+    pub fn allows(
+        &self,
+        user: Id<UserMarker>,
+        roles: &[Id<RoleMarker>],
+        permissions: Permissions,
+    ) -> bool {
+        if self.denied_users.contains(&user) {
+            return false;
+        }
+        if self.allowed_users.contains(&user) {
+            return true;
+        }
+        if roles.iter().any(|role| self.denied_roles.contains(role)) {
+            return false;
+        }
+        if roles.iter().any(|role| self.allowed_roles.contains(role)) {
+            return true;
+        }
+        // Discord doesn't expand ADMINISTRATOR into the permissions it implies.
+        !self.permissions.is_empty()
+            && (permissions.contains(self.permissions)
+                || permissions.contains(Permissions::ADMINISTRATOR))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: Id<UserMarker> = Id::new(1);
+    const ALLOWED: Id<RoleMarker> = Id::new(10);
+    const DENIED: Id<RoleMarker> = Id::new(11);
+
+    fn policy() -> AccessPolicy {
+        AccessPolicy {
+            permissions: Permissions::MANAGE_MESSAGES,
+            allowed_roles: HashSet::from([ALLOWED]),
+            denied_roles: HashSet::from([DENIED]),
+            allowed_users: HashSet::new(),
+            denied_users: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn denied_user_beats_everything() {
+        let mut policy = policy();
+        policy.denied_users.insert(USER);
+        policy.allowed_users.insert(USER);
+        assert!(!policy.allows(USER, &[ALLOWED], Permissions::ADMINISTRATOR));
+    }
+
+    #[test]
+    fn allowed_user_beats_denied_roles() {
+        let mut policy = policy();
+        policy.allowed_users.insert(USER);
+        assert!(policy.allows(USER, &[DENIED], Permissions::empty()));
+    }
+
+    #[test]
+    fn denied_role_beats_allowed_role_and_permissions() {
+        let policy = policy();
+        assert!(!policy.allows(USER, &[ALLOWED, DENIED], Permissions::ADMINISTRATOR));
+    }
+
+    #[test]
+    fn allowed_role_needs_no_permissions() {
+        assert!(policy().allows(USER, &[ALLOWED], Permissions::empty()));
+    }
+
+    #[test]
+    fn permissions_are_checked_last() {
+        let policy = policy();
+        assert!(policy.allows(USER, &[], Permissions::MANAGE_MESSAGES));
+        assert!(policy.allows(USER, &[], Permissions::ADMINISTRATOR));
+        assert!(!policy.allows(USER, &[], Permissions::SEND_MESSAGES));
+    }
+
+    #[test]
+    fn empty_permissions_let_nobody_in() {
+        let mut policy = policy();
+        policy.permissions = Permissions::empty();
+        assert!(!policy.allows(USER, &[], Permissions::ADMINISTRATOR));
+    }
+}
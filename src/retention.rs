@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use redis::AsyncCommands;
+use time::OffsetDateTime;
+
+use crate::{
+    collection::{delete_collection, exists, update_manifest, Manifest},
+    AppState, Error,
+};
+
+/// Sorted set of collection IDs, scored by the unix timestamp they expire at.
+const EXPIRY_KEY: &str = "collections:expiry";
+const SWEEP_INTERVAL: Duration = Duration::from_mins(10);
+
+/// Keep the expiry index in sync with a collection's manifest. `None` means the
+/// collection is kept until someone deletes it.
+pub async fn schedule(
+    state: &AppState,
+    id: &str,
+    expires_at: Option<OffsetDateTime>,
+) -> Result<(), Error> {
+    let mut redis = state.redis.clone();
+    if let Some(expires_at) = expires_at {
+        let _: () = redis
+            .zadd(EXPIRY_KEY, id, expires_at.unix_timestamp())
+            .await?;
+    } else {
+        let _: () = redis.zrem(EXPIRY_KEY, id).await?;
+    }
+    Ok(())
+}
+
+/// Override how long a single collection is kept, counted from when it was
+/// saved. Collections from before manifests existed get one, and their
+/// retention counts from now.
+#[instrument(skip(state))]
+pub async fn set_retention(
+    state: &AppState,
+    id: &str,
+    retention: Option<time::Duration>,
+) -> Result<(), Error> {
+    if !exists(state, id).await? {
+        return Err(Error::NotFound);
+    }
+    update_manifest(state, id, |manifest| {
+        let mut manifest = manifest.unwrap_or_else(|| Manifest::legacy(None));
+        manifest.expires_at = expiry(manifest.created_at, retention, OffsetDateTime::now_utc())?;
+        Ok(manifest)
+    })
+    .await
+}
+
+/// A retention that has already run out would have the next sweep delete the
+/// collection without anyone confirming it, so it's refused.
+fn expiry(
+    created_at: OffsetDateTime,
+    retention: Option<time::Duration>,
+    now: OffsetDateTime,
+) -> Result<Option<OffsetDateTime>, Error> {
+    let expires_at = retention.map(|retention| created_at + retention);
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(Error::RetentionElapsed);
+    }
+    Ok(expires_at)
+}
+
+pub async fn sweeper(state: AppState) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(source) = sweep(&state).await {
+            error!(?source, "Failed to delete expired collections");
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn sweep(state: &AppState) -> Result<(), Error> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let expired: Vec<String> = state
+        .redis
+        .clone()
+        .zrangebyscore(EXPIRY_KEY, "-inf", now)
+        .await?;
+    for id in expired {
+        match delete_collection(state, &id).await {
+            Ok(removed) => info!(id, removed, "Deleted expired collection"),
+            Err(Error::LegalHold) => debug!(id, "Kept expired collection under legal hold"),
+            Err(source) => error!(id, ?source, "Failed to delete expired collection"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn day(n: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(n * DAY).unwrap()
+    }
+
+    #[test]
+    fn retention_counts_from_saving() {
+        let expires_at = expiry(day(0), Some(time::Duration::days(30)), day(9)).unwrap();
+        assert_eq!(expires_at, Some(day(30)));
+    }
+
+    #[test]
+    fn no_retention_keeps_forever() {
+        assert_eq!(expiry(day(0), None, day(9)).unwrap(), None);
+    }
+
+    #[test]
+    fn elapsed_retention_is_refused() {
+        for days in [0, 5, 9] {
+            assert!(matches!(
+                expiry(day(0), Some(time::Duration::days(days)), day(9)),
+                Err(Error::RetentionElapsed)
+            ));
+        }
+    }
+}
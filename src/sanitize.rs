@@ -0,0 +1,514 @@
+use std::io::Cursor;
+
+use axum::body::Bytes;
+use exif::{Context, Tag};
+use image::{metadata::Orientation, ImageDecoder, ImageError, ImageReader, Limits};
+use img_parts::{
+    jpeg::{markers, Jpeg},
+    png::{Png, PngChunk},
+    riff::{RiffChunk, RiffContent},
+    webp::{WebP, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8X, CHUNK_XMP},
+    DynImage, ImageEXIF,
+};
+use serde::{Deserialize, Serialize};
+
+/// PNG chunks that hold metadata rather than pixels. Text chunks are where
+/// XMP and most editors' other metadata end up.
+const PNG_METADATA_CHUNKS: [[u8; 4]; 6] =
+    [*b"eXIf", *b"iCCP", *b"iTXt", *b"tEXt", *b"zTXt", *b"tIME"];
+/// Bits of the `VP8X` flags byte announcing an ICC profile, EXIF and XMP.
+const WEBP_ICC_FLAG: u8 = 0b0010_0000;
+const WEBP_EXIF_FLAG: u8 = 0b0000_1000;
+const WEBP_XMP_FLAG: u8 = 0b0000_0100;
+/// GIF application extensions that control how an animation loops. Any other
+/// application, like XMP or an ICC profile, is metadata.
+const GIF_LOOP_APPLICATIONS: [&[u8]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+/// Identifying metadata an image carried when it was saved. Only the kinds
+/// are recorded, never the values.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Metadata {
+    Gps,
+    Camera,
+    CaptureTime,
+    Author,
+    Exif,
+    Xmp,
+    Icc,
+    /// Metadata that couldn't be read, so what it held is unknown.
+    Unreadable,
+}
+
+impl Metadata {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Gps => "GPS location",
+            Self::Camera => "camera details",
+            Self::CaptureTime => "capture time",
+            Self::Author => "author",
+            Self::Exif => "EXIF",
+            Self::Xmp => "XMP",
+            Self::Icc => "ICC profile",
+            Self::Unreadable => "unreadable metadata",
+        }
+    }
+}
+
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An image after its metadata has been dealt with.
+pub struct Sanitized {
+    /// The file with its metadata removed, except for its orientation. `None`
+    /// unless it's in a format we can rewrite without its metadata.
+    pub data: Option<Bytes>,
+    /// How the pixels have to be turned to display the right way up.
+    pub orientation: Orientation,
+    pub found: Vec<Metadata>,
+}
+
+impl Sanitized {
+    const fn unknown(found: Vec<Metadata>) -> Self {
+        Self {
+            data: None,
+            orientation: Orientation::NoTransforms,
+            found,
+        }
+    }
+}
+
+/// Find what metadata `data` carries and remove it. Only JPEG, PNG, WebP and
+/// GIF files can be rewritten, anything else is converted but never kept as
+/// is. Failing to read the metadata doesn't lose the image, it's recorded as
+/// unreadable and the original isn't kept.
+#[instrument(skip_all)]
+pub fn sanitize(data: &Bytes, limits: Limits) -> Sanitized {
+    let decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(ImageError::IoError)
+        .and_then(|mut reader| {
+            reader.limits(limits);
+            reader.into_decoder()
+        });
+    let mut decoder = match decoder {
+        Ok(decoder) => decoder,
+        // Conversion will fail too, and say why.
+        Err(source) => {
+            debug!(?source, "Failed to read image for metadata");
+            return Sanitized::unknown(Vec::new());
+        }
+    };
+    let metadata = decoder.exif_metadata().and_then(|exif| {
+        let xmp = decoder.xmp_metadata()?;
+        let icc = decoder.icc_profile()?;
+        Ok((exif, xmp, icc))
+    });
+    let (exif, xmp, icc) = match metadata {
+        Ok(metadata) => metadata,
+        Err(source) => {
+            warn!(?source, "Failed to read metadata");
+            return Sanitized::unknown(vec![Metadata::Unreadable]);
+        }
+    };
+    // A broken orientation tag shouldn't lose the image, just its rotation.
+    let orientation = decoder.orientation().unwrap_or_else(|source| {
+        debug!(?source, "Failed to read orientation");
+        Orientation::NoTransforms
+    });
+    let mut found = exif.map(classify_exif).unwrap_or_default();
+    if xmp.is_some() {
+        found.push(Metadata::Xmp);
+    }
+    if icc.is_some() {
+        found.push(Metadata::Icc);
+    }
+    found.sort_unstable();
+    found.dedup();
+    if !found.is_empty() {
+        trace!(?found, "Found metadata");
+    }
+
+    Sanitized {
+        data: strip(data.clone(), orientation),
+        orientation,
+        found,
+    }
+}
+
+/// Sort the fields of an EXIF block into the kinds moderators care about.
+fn classify_exif(exif: Vec<u8>) -> Vec<Metadata> {
+    let mut found = vec![Metadata::Exif];
+    let Ok(exif) = exif::Reader::new().read_raw(exif) else {
+        return found;
+    };
+    for field in exif.fields() {
+        let kind = match field.tag {
+            tag if tag.context() == Context::Gps && tag != Tag::GPSVersionID => Metadata::Gps,
+            Tag::Make
+            | Tag::Model
+            | Tag::LensMake
+            | Tag::LensModel
+            | Tag::BodySerialNumber
+            | Tag::LensSerialNumber => Metadata::Camera,
+            Tag::DateTime | Tag::DateTimeOriginal | Tag::DateTimeDigitized => Metadata::CaptureTime,
+            Tag::Artist | Tag::Copyright | Tag::CameraOwnerName => Metadata::Author,
+            _ => continue,
+        };
+        found.push(kind);
+    }
+    found
+}
+
+/// Remove EXIF, XMP, ICC and similar blocks without touching the pixels, and
+/// put back just the orientation so the file still displays the same way.
+/// Returns `None` for formats this can't be done for.
+fn strip(data: Bytes, orientation: Orientation) -> Option<Bytes> {
+    // GIFs have no EXIF, so no orientation to put back either.
+    if data.starts_with(b"GIF8") {
+        return strip_gif(&data);
+    }
+    let exif = (orientation != Orientation::NoTransforms).then(|| orientation_exif(orientation));
+    let image = match DynImage::from_bytes(data).ok()?? {
+        DynImage::Jpeg(jpeg) => DynImage::Jpeg(strip_jpeg(jpeg, exif)),
+        DynImage::Png(png) => DynImage::Png(strip_png(png, exif)),
+        DynImage::WebP(webp) => DynImage::WebP(strip_webp(webp, exif)?),
+    };
+    Some(image.encoder().bytes())
+}
+
+/// Only the JFIF header in `APP0` and Adobe's `APP14`, which says how CMYK
+/// and YCCK colours are stored, are needed to display a JPEG. The other
+/// application segments hold EXIF, XMP, ICC profiles, IPTC and whatever
+/// else the camera or editor chose to put there.
+fn strip_jpeg(mut jpeg: Jpeg, exif: Option<Bytes>) -> Jpeg {
+    jpeg.segments_mut().retain(|segment| {
+        let marker = segment.marker();
+        !(matches!(marker, markers::APP1..=markers::APP13 | markers::APP15)
+            || marker == markers::COM)
+    });
+    jpeg.set_exif(exif);
+    jpeg
+}
+
+fn strip_png(mut png: Png, exif: Option<Bytes>) -> Png {
+    png.chunks_mut()
+        .retain(|chunk| !PNG_METADATA_CHUNKS.contains(&chunk.kind()));
+    if let Some(exif) = exif {
+        // Readers only look for eXIf before the image data, so it goes
+        // straight after the header.
+        png.chunks_mut().insert(1, PngChunk::new(*b"eXIf", exif));
+    }
+    png
+}
+
+/// `img-parts` rewrites the `VP8X` header in ways that break animated and
+/// transparent images, so the chunks and flags are edited here directly.
+fn strip_webp(mut webp: WebP, exif: Option<Bytes>) -> Option<WebP> {
+    webp.remove_chunks_by_id(CHUNK_XMP);
+    webp.remove_chunks_by_id(CHUNK_ICCP);
+    webp.remove_chunks_by_id(CHUNK_EXIF);
+    // Simple WebPs have nowhere to put metadata, or to announce it.
+    let Some(vp8x) = webp
+        .chunks_mut()
+        .iter_mut()
+        .find(|chunk| chunk.id() == CHUNK_VP8X)
+    else {
+        return Some(webp);
+    };
+    let RiffContent::Data(header) = vp8x.content_mut() else {
+        return None;
+    };
+    let mut flags = header.to_vec();
+    *flags.first_mut()? &= !(WEBP_ICC_FLAG | WEBP_XMP_FLAG | WEBP_EXIF_FLAG);
+    if exif.is_some() {
+        flags[0] |= WEBP_EXIF_FLAG;
+    }
+    *header = flags.into();
+    if let Some(exif) = exif {
+        webp.chunks_mut()
+            .push(RiffChunk::new(CHUNK_EXIF, RiffContent::Data(exif)));
+    }
+    Some(webp)
+}
+
+/// Copy a GIF block by block, leaving out comments and application
+/// extensions other than looping. Returns `None` if the file ends early or
+/// holds a block GIF doesn't define.
+fn strip_gif(data: &[u8]) -> Option<Bytes> {
+    // Header and logical screen descriptor, then the global colour table.
+    let mut pos = 13 + color_table_len(*data.get(10)?);
+    let mut out = data.get(..pos)?.to_vec();
+    loop {
+        match *data.get(pos)? {
+            // Image descriptor, local colour table, LZW code size, then data.
+            0x2c => {
+                let start = pos;
+                pos += 10 + color_table_len(*data.get(pos + 9)?) + 1;
+                pos = skip_sub_blocks(data, pos)?;
+                out.extend_from_slice(data.get(start..pos)?);
+            }
+            0x21 => {
+                let start = pos;
+                let label = *data.get(pos + 1)?;
+                pos = skip_sub_blocks(data, pos + 2)?;
+                let keep = match label {
+                    0xfe => false,
+                    0xff => {
+                        let len = usize::from(*data.get(start + 2)?);
+                        let application = data.get(start + 3..start + 3 + len)?;
+                        GIF_LOOP_APPLICATIONS.contains(&application)
+                    }
+                    _ => true,
+                };
+                if keep {
+                    out.extend_from_slice(data.get(start..pos)?);
+                }
+            }
+            // Trailer. Anything after it isn't part of the image.
+            0x3b => {
+                out.push(0x3b);
+                return Some(out.into());
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Bytes taken by the colour table a GIF descriptor's packed field announces.
+const fn color_table_len(packed: u8) -> usize {
+    if packed & 0x80 == 0 {
+        0
+    } else {
+        3 << ((packed & 0x07) + 1)
+    }
+}
+
+/// Position just past the zero-length block ending the sub-blocks at `pos`.
+fn skip_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = usize::from(*data.get(pos)?);
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// A little-endian TIFF block with a single IFD holding only the orientation.
+fn orientation_exif(orientation: Orientation) -> Bytes {
+    let mut exif = Vec::with_capacity(26);
+    exif.extend_from_slice(b"II");
+    exif.extend_from_slice(&42u16.to_le_bytes());
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&0x0112u16.to_le_bytes());
+    // One SHORT, stored inline and padded to four bytes.
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&u16::from(orientation.to_exif()).to_le_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{In, Value};
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use img_parts::{jpeg::JpegSegment, webp::CHUNK_ANIM};
+
+    use super::*;
+
+    const WEBP_ALPHA_FLAG: u8 = 0b0001_0000;
+    const WEBP_ANIMATION_FLAG: u8 = 0b0000_0010;
+
+    fn encode(format: ImageFormat) -> Bytes {
+        let mut data = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(2, 2))
+            .write_to(&mut data, format)
+            .unwrap();
+        data.into_inner().into()
+    }
+
+    /// A little-endian TIFF block whose only field is one GPS tag, reached
+    /// through IFD0's GPS pointer.
+    fn gps_exif(tag: u16, kind: u16, value: [u8; 4]) -> Vec<u8> {
+        let mut exif = Vec::new();
+        exif.extend_from_slice(b"II");
+        exif.extend_from_slice(&42u16.to_le_bytes());
+        exif.extend_from_slice(&8u32.to_le_bytes());
+        exif.extend_from_slice(&1u16.to_le_bytes());
+        exif.extend_from_slice(&0x8825u16.to_le_bytes());
+        exif.extend_from_slice(&4u16.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+        exif.extend_from_slice(&26u32.to_le_bytes());
+        exif.extend_from_slice(&0u32.to_le_bytes());
+        exif.extend_from_slice(&1u16.to_le_bytes());
+        exif.extend_from_slice(&tag.to_le_bytes());
+        exif.extend_from_slice(&kind.to_le_bytes());
+        let count = if kind == 2 { 2u32 } else { 4 };
+        exif.extend_from_slice(&count.to_le_bytes());
+        exif.extend_from_slice(&value);
+        exif.extend_from_slice(&0u32.to_le_bytes());
+        exif
+    }
+
+    #[test]
+    fn orientation_exif_reads_back() {
+        let exif = exif::Reader::new()
+            .read_raw(orientation_exif(Orientation::Rotate90).to_vec())
+            .unwrap();
+        let field = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+        assert!(matches!(field.value, Value::Short(ref v) if v == &[6]));
+    }
+
+    #[test]
+    fn classify_exif_finds_gps() {
+        let found = classify_exif(gps_exif(0x0001, 2, *b"N\0\0\0"));
+        assert_eq!(found, [Metadata::Exif, Metadata::Gps]);
+    }
+
+    #[test]
+    fn gps_version_alone_is_no_location() {
+        let found = classify_exif(gps_exif(0x0000, 1, [2, 2, 0, 0]));
+        assert_eq!(found, [Metadata::Exif]);
+    }
+
+    #[test]
+    fn strip_jpeg_keeps_only_jfif_and_adobe() {
+        let mut jpeg = Jpeg::from_bytes(encode(ImageFormat::Jpeg)).unwrap();
+        for marker in [
+            markers::APP1,
+            markers::APP2,
+            markers::APP13,
+            markers::APP14,
+            markers::COM,
+        ] {
+            jpeg.segments_mut().insert(
+                1,
+                JpegSegment::new_with_contents(marker, Bytes::from_static(b"secret")),
+            );
+        }
+        let exif = orientation_exif(Orientation::Rotate180);
+        let jpeg = strip_jpeg(jpeg, Some(exif.clone()));
+        let kept: Vec<_> = jpeg
+            .segments()
+            .iter()
+            .map(JpegSegment::marker)
+            .filter(|marker| matches!(*marker, markers::APP0..=markers::APP15 | markers::COM))
+            .collect();
+        assert_eq!(kept, [markers::APP0, markers::APP14, markers::APP1]);
+        assert_eq!(jpeg.exif(), Some(exif));
+    }
+
+    #[test]
+    fn strip_gif_keeps_only_looping() {
+        let gif = encode(ImageFormat::Gif);
+        let header = 13 + color_table_len(gif[10]);
+        let looping = b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00";
+        let mut data = gif[..header].to_vec();
+        data.extend_from_slice(b"\x21\xfe\x06secret\x00");
+        data.extend_from_slice(b"\x21\xff\x0bXMP DataXMP\x06secret\x00");
+        data.extend_from_slice(looping);
+        data.extend_from_slice(&gif[header..]);
+
+        let stripped = strip_gif(&data).unwrap();
+        let mut expected = gif[..header].to_vec();
+        expected.extend_from_slice(looping);
+        expected.extend_from_slice(&gif[header..]);
+        assert_eq!(stripped, expected);
+        assert!(strip_gif(&data[..data.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn strip_png_puts_exif_before_image_data() {
+        let mut png = Png::from_bytes(encode(ImageFormat::Png)).unwrap();
+        png.chunks_mut().insert(
+            1,
+            PngChunk::new(*b"tEXt", Bytes::from_static(b"Author\0someone")),
+        );
+        let png = strip_png(png, Some(orientation_exif(Orientation::FlipHorizontal)));
+        let kinds: Vec<_> = png.chunks().iter().map(PngChunk::kind).collect();
+        let exif = kinds.iter().position(|kind| kind == b"eXIf").unwrap();
+        let data = kinds.iter().position(|kind| kind == b"IDAT").unwrap();
+        assert!(exif < data);
+        assert!(!kinds.contains(b"tEXt"));
+    }
+
+    #[test]
+    fn strip_webp_keeps_alpha_and_animation() {
+        let flags =
+            WEBP_ICC_FLAG | WEBP_ALPHA_FLAG | WEBP_EXIF_FLAG | WEBP_XMP_FLAG | WEBP_ANIMATION_FLAG;
+        let chunk = |id, data: Vec<u8>| RiffChunk::new(id, RiffContent::Data(data.into()));
+        let webp = WebP::new(RiffChunk::new(
+            *b"RIFF",
+            RiffContent::List {
+                kind: Some(*b"WEBP"),
+                subchunks: vec![
+                    chunk(CHUNK_VP8X, vec![flags, 0, 0, 0, 1, 0, 0, 1, 0, 0]),
+                    chunk(CHUNK_ICCP, b"icc".to_vec()),
+                    chunk(CHUNK_ANIM, vec![0; 6]),
+                    chunk(CHUNK_EXIF, b"exif".to_vec()),
+                    chunk(CHUNK_XMP, b"xmp".to_vec()),
+                ],
+            },
+        ))
+        .unwrap();
+
+        let stripped = strip_webp(webp.clone(), None).unwrap();
+        let ids: Vec<_> = stripped.chunks().iter().map(RiffChunk::id).collect();
+        assert_eq!(ids, [CHUNK_VP8X, CHUNK_ANIM]);
+        let header = stripped
+            .chunk_by_id(CHUNK_VP8X)
+            .unwrap()
+            .content()
+            .data()
+            .unwrap();
+        assert_eq!(header[0], WEBP_ALPHA_FLAG | WEBP_ANIMATION_FLAG);
+
+        let exif = orientation_exif(Orientation::Rotate270);
+        let stripped = strip_webp(webp, Some(exif.clone())).unwrap();
+        let header = stripped
+            .chunk_by_id(CHUNK_VP8X)
+            .unwrap()
+            .content()
+            .data()
+            .unwrap();
+        assert_eq!(
+            header[0],
+            WEBP_ALPHA_FLAG | WEBP_ANIMATION_FLAG | WEBP_EXIF_FLAG
+        );
+        let stored = stripped
+            .chunk_by_id(CHUNK_EXIF)
+            .unwrap()
+            .content()
+            .data()
+            .unwrap();
+        assert_eq!(stored, &exif);
+    }
+
+    #[test]
+    fn other_formats_are_not_kept() {
+        let sanitized = sanitize(&encode(ImageFormat::Bmp), Limits::default());
+        assert!(sanitized.data.is_none());
+        assert!(sanitized.found.is_empty());
+    }
+
+    #[test]
+    fn supported_formats_are_kept() {
+        for format in [
+            ImageFormat::Jpeg,
+            ImageFormat::Png,
+            ImageFormat::WebP,
+            ImageFormat::Gif,
+        ] {
+            let sanitized = sanitize(&encode(format), Limits::default());
+            assert!(sanitized.data.is_some(), "{format:?}");
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use twilight_model::{
+    channel::{
+        message::{Embed, Mention, MessageReferenceType},
+        Message,
+    },
+    id::{
+        marker::{ChannelMarker, GuildMarker, MessageMarker, RoleMarker, UserMarker},
+        Id,
+    },
+    user::User,
+    util::{ImageHash, Timestamp},
+};
+
+use crate::collection::{jump_url, Person};
+
+/// Milliseconds between the unix epoch and the first second of 2015, which
+/// Discord's snowflakes count from.
+const DISCORD_EPOCH_MILLIS: u64 = 1_420_070_400_000;
+/// How much of a replied-to message is kept for context.
+const REPLY_EXCERPT_CHARS: usize = 100;
+
+/// What a message said when it was saved, so its text outlives the message.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MessageSnapshot {
+    pub author: Author,
+    pub content: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub sent_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub edited_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub mentions: Vec<Person>,
+    #[serde(default)]
+    pub mention_roles: Vec<Id<RoleMarker>>,
+    #[serde(default)]
+    pub mention_everyone: bool,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+    pub reply_to: Option<Reply>,
+    /// Messages forwarded into this one, which carry their own content.
+    #[serde(default)]
+    pub forwarded: Vec<Forward>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Author {
+    pub id: Id<UserMarker>,
+    pub name: String,
+    pub global_name: Option<String>,
+    pub avatar: Option<ImageHash>,
+}
+
+/// The message a snapshot was replying to. Discord only includes its author
+/// and content if it still existed when the reply was fetched.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reply {
+    pub guild_id: Option<Id<GuildMarker>>,
+    pub channel_id: Id<ChannelMarker>,
+    pub message_id: Id<MessageMarker>,
+    pub author: Option<Person>,
+    pub excerpt: Option<String>,
+}
+
+/// A forwarded copy of another message. Discord doesn't say who wrote the
+/// original, only where it was, and the reference is missing if the original
+/// couldn't be resolved.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Forward {
+    pub guild_id: Option<Id<GuildMarker>>,
+    pub channel_id: Option<Id<ChannelMarker>>,
+    pub message_id: Option<Id<MessageMarker>>,
+    pub content: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub sent_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub edited_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub mentions: Vec<Person>,
+    #[serde(default)]
+    pub mention_roles: Vec<Id<RoleMarker>>,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+}
+
+/// A user's names as they were when their profile was saved. The avatar and
+/// banner images are the collection's attachments.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProfileSnapshot {
+    pub id: Id<UserMarker>,
+    pub name: String,
+    pub global_name: Option<String>,
+    /// Nickname in the guild the profile was saved from.
+    pub nick: Option<String>,
+    pub bot: bool,
+}
+
+impl ProfileSnapshot {
+    #[must_use]
+    pub fn new(user: &User, nick: Option<String>) -> Self {
+        Self {
+            id: user.id,
+            name: user.name.clone(),
+            global_name: user.global_name.clone(),
+            nick,
+            bot: user.bot,
+        }
+    }
+
+    /// Read from the user's snowflake, as new accounts are a common sign of
+    /// impersonation.
+    #[must_use]
+    pub fn created_at_string(&self) -> String {
+        let millis = (self.id.get() >> 22) + DISCORD_EPOCH_MILLIS;
+        OffsetDateTime::from_unix_timestamp_nanos(i128::from(millis) * 1_000_000)
+            .map_or_else(|_| millis.to_string(), crate::format_time)
+    }
+}
+
+impl MessageSnapshot {
+    #[must_use]
+    pub fn from_message(message: &Message) -> Self {
+        Self {
+            author: Author {
+                id: message.author.id,
+                name: message.author.name.clone(),
+                global_name: message.author.global_name.clone(),
+                avatar: message.author.avatar,
+            },
+            content: message.content.clone(),
+            sent_at: to_offset_date_time(message.timestamp),
+            edited_at: message.edited_timestamp.map(to_offset_date_time),
+            mentions: to_people(&message.mentions),
+            mention_roles: message.mention_roles.clone(),
+            mention_everyone: message.mention_everyone,
+            embeds: message.embeds.clone(),
+            reply_to: Reply::from_message(message),
+            forwarded: Forward::from_message(message),
+        }
+    }
+
+    /// Nothing worth keeping without attachments.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty()
+            && self.embeds.is_empty()
+            && self
+                .forwarded
+                .iter()
+                .all(|forward| forward.content.is_empty() && forward.embeds.is_empty())
+    }
+
+    #[must_use]
+    pub fn rendered_content(&self) -> String {
+        render_mentions(&self.content, &self.mentions)
+    }
+
+    #[must_use]
+    pub fn sent_at_string(&self) -> String {
+        crate::format_time(self.sent_at)
+    }
+
+    #[must_use]
+    pub fn edited_at_string(&self) -> Option<String> {
+        self.edited_at.map(crate::format_time)
+    }
+}
+
+impl Author {
+    #[must_use]
+    pub fn display_name(&self) -> &str {
+        self.global_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+impl Forward {
+    fn from_message(message: &Message) -> Vec<Self> {
+        let reference = message
+            .reference
+            .as_ref()
+            .filter(|reference| reference.kind == MessageReferenceType::Forward);
+        message
+            .message_snapshots
+            .iter()
+            .map(|snapshot| {
+                let fields = &snapshot.message;
+                Self {
+                    guild_id: snapshot
+                        .guild_id
+                        .or_else(|| reference.and_then(|r| r.guild_id)),
+                    channel_id: reference.and_then(|r| r.channel_id),
+                    message_id: reference.and_then(|r| r.message_id),
+                    content: fields.content.clone(),
+                    sent_at: to_offset_date_time(fields.timestamp),
+                    edited_at: fields.edited_timestamp.map(to_offset_date_time),
+                    mentions: to_people(&fields.mentions),
+                    mention_roles: fields.mention_roles.clone(),
+                    embeds: fields.embeds.clone(),
+                }
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn rendered_content(&self) -> String {
+        render_mentions(&self.content, &self.mentions)
+    }
+
+    #[must_use]
+    pub fn sent_at_string(&self) -> String {
+        crate::format_time(self.sent_at)
+    }
+
+    #[must_use]
+    pub fn jump_url(&self) -> Option<String> {
+        Some(jump_url(self.guild_id, self.channel_id?, self.message_id?))
+    }
+}
+
+impl Reply {
+    fn from_message(message: &Message) -> Option<Self> {
+        let reference = message.reference.as_ref()?;
+        if reference.kind != MessageReferenceType::Default {
+            return None;
+        }
+        let replied = message.referenced_message.as_deref();
+        Some(Self {
+            guild_id: reference.guild_id.or(message.guild_id),
+            channel_id: reference.channel_id.unwrap_or(message.channel_id),
+            message_id: reference.message_id?,
+            author: replied.map(|replied| Person {
+                id: replied.author.id,
+                name: replied.author.name.clone(),
+            }),
+            excerpt: replied
+                .filter(|replied| !replied.content.is_empty())
+                .map(|replied| excerpt(&replied.content)),
+        })
+    }
+
+    #[must_use]
+    pub fn jump_url(&self) -> String {
+        jump_url(self.guild_id, self.channel_id, self.message_id)
+    }
+}
+
+/// The avatar Discord shows for users who haven't set one.
+#[must_use]
+pub fn default_avatar_url(user: Id<UserMarker>) -> String {
+    format!(
+        "https://cdn.discordapp.com/embed/avatars/{}.png",
+        (user.get() >> 22) % 6
+    )
+}
+
+fn to_people(mentions: &[Mention]) -> Vec<Person> {
+    mentions
+        .iter()
+        .map(|mention| Person {
+            id: mention.id,
+            name: mention.name.clone(),
+        })
+        .collect()
+}
+
+/// The content with user mentions shown as names, like Discord does.
+fn render_mentions(content: &str, mentions: &[Person]) -> String {
+    let mut content = content.to_owned();
+    for person in mentions {
+        let name = format!("@{}", person.name);
+        content = content
+            .replace(&format!("<@{}>", person.id), &name)
+            .replace(&format!("<@!{}>", person.id), &name);
+    }
+    content
+}
+
+fn excerpt(content: &str) -> String {
+    let mut chars = content.chars();
+    let mut excerpt: String = chars.by_ref().take(REPLY_EXCERPT_CHARS).collect();
+    if chars.next().is_some() {
+        excerpt.push('…');
+    }
+    excerpt
+}
+
+fn to_offset_date_time(timestamp: Timestamp) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(timestamp.as_micros()) * 1000)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn person(id: u64, name: &str) -> Person {
+        Person {
+            id: Id::new(id),
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn mentions_show_names() {
+        let mentions = [person(1, "alice"), person(2, "bob")];
+        assert_eq!(
+            render_mentions("<@1> and <@!2>, <@1> again", &mentions),
+            "@alice and @bob, @alice again"
+        );
+    }
+
+    #[test]
+    fn unresolved_mentions_are_left_alone() {
+        let mentions = [person(1, "alice")];
+        assert_eq!(
+            render_mentions("<@3> <@&1> <#1>", &mentions),
+            "<@3> <@&1> <#1>"
+        );
+    }
+
+    #[test]
+    fn default_avatars_follow_the_snowflake() {
+        assert_eq!(
+            default_avatar_url(Id::new(1)),
+            "https://cdn.discordapp.com/embed/avatars/0.png"
+        );
+        assert_eq!(
+            default_avatar_url(Id::new(5 << 22)),
+            "https://cdn.discordapp.com/embed/avatars/5.png"
+        );
+        assert_eq!(
+            default_avatar_url(Id::new(6 << 22)),
+            "https://cdn.discordapp.com/embed/avatars/0.png"
+        );
+    }
+
+    #[test]
+    fn short_replies_are_kept_whole() {
+        assert_eq!(excerpt("hello"), "hello");
+        let exact = "a".repeat(REPLY_EXCERPT_CHARS);
+        assert_eq!(excerpt(&exact), exact);
+    }
+
+    #[test]
+    fn long_replies_are_cut_on_characters() {
+        let long = "é".repeat(REPLY_EXCERPT_CHARS + 1);
+        let cut = excerpt(&long);
+        assert_eq!(cut.chars().count(), REPLY_EXCERPT_CHARS + 1);
+        assert!(cut.ends_with('…'));
+    }
+
+    #[test]
+    fn timestamps_keep_microseconds() {
+        let timestamp = Timestamp::from_micros(1_700_000_000_123_456).unwrap();
+        let converted = to_offset_date_time(timestamp);
+        assert_eq!(converted.unix_timestamp(), 1_700_000_000);
+        assert_eq!(converted.microsecond(), 123_456);
+    }
+}
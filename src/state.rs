@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashSet, str::FromStr, sync::Arc, time::Duration};
 
 use oauth2::{
     basic::{
@@ -8,16 +8,21 @@ use oauth2::{
     AuthUrl, ClientId, ClientSecret, EndpointNotSet, EndpointSet, RedirectUrl, RevocationUrl,
     StandardRevocableToken, TokenUrl,
 };
-use redis::{aio::MultiplexedConnection, AsyncCommands};
+use redis::aio::MultiplexedConnection;
 use reqwest::{Client, ClientBuilder};
 use s3::{creds::Credentials, Bucket, Region};
-use twilight_model::id::{
-    marker::{ApplicationMarker, GuildMarker},
-    Id,
+use time::OffsetDateTime;
+use tokio::sync::Semaphore;
+use twilight_model::{
+    guild::Permissions,
+    id::{
+        marker::{ApplicationMarker, GuildMarker, UserMarker},
+        Id,
+    },
 };
 use valk_utils::{get_var, parse_var};
 
-use crate::{signature_validation::Key, Error};
+use crate::{policy::AccessPolicy, signature_validation::Key, upload::UploadLimits};
 
 #[derive(Clone)]
 #[allow(clippy::module_name_repetitions)]
@@ -25,10 +30,20 @@ pub struct AppState {
     pub bucket: Arc<Bucket>,
     pub http: Client,
     pub redis: MultiplexedConnection,
-    pub guild: Id<GuildMarker>,
+    pub guilds: Arc<HashSet<Id<GuildMarker>>>,
+    /// Register commands in each guild for the bot, rather than globally for users.
+    pub guild_install: bool,
     pub oauth: Arc<OAuth2Client>,
     pub discord: Arc<Discord>,
     pub root_url: Arc<str>,
+    pub keep_originals: bool,
+    pub default_retention: Option<time::Duration>,
+    pub admins: Arc<HashSet<Id<UserMarker>>>,
+    pub policy: Arc<AccessPolicy>,
+    pub limits: UploadLimits,
+    /// Permits for converting images, each of which can use up to
+    /// `limits.decoded_bytes` of memory.
+    pub conversions: Arc<Semaphore>,
 }
 
 impl AppState {
@@ -40,17 +55,29 @@ impl AppState {
             bucket: get_bucket().into(),
             http: get_http(),
             redis: get_redis().await,
-            guild: parse_var("GUILD"),
+            guilds: Arc::new(get_guilds()),
+            guild_install: std::env::var("GUILD_INSTALL")
+                .as_deref()
+                .is_ok_and(crate::check_truthy),
             oauth: get_oauth(discord.application_id, &root_url).into(),
             discord,
             root_url,
+            keep_originals: std::env::var("KEEP_ORIGINALS")
+                .as_deref()
+                .is_ok_and(crate::check_truthy),
+            default_retention: parse_optional_var("RETENTION_DAYS").map(time::Duration::days),
+            admins: Arc::new(parse_id_list("ADMIN_USERS")),
+            policy: Arc::new(get_access_policy()),
+            limits: get_upload_limits(),
+            conversions: Arc::new(Semaphore::new(get_max_conversions())),
         }
     }
 
-    #[allow(clippy::missing_errors_doc)]
-    pub async fn redis_exists(&mut self, key: &str) -> Result<bool, Error> {
-        let value: Option<bool> = self.redis.get(key).await?;
-        Ok(value.is_some())
+    /// When a collection saved right now should be deleted, if ever.
+    #[must_use]
+    pub fn default_expiry(&self) -> Option<OffsetDateTime> {
+        self.default_retention
+            .map(|retention| OffsetDateTime::now_utc() + retention)
     }
 
     #[must_use]
@@ -64,6 +91,80 @@ impl AppState {
     }
 }
 
+fn parse_optional_var<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).is_ok().then(|| parse_var(name))
+}
+
+/// Comma-separated snowflakes. Unset means an empty list.
+fn parse_id_list<T>(name: &str) -> HashSet<Id<T>> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .unwrap_or_else(|_| panic!("Could not parse {id} in {name} as a snowflake!"))
+        })
+        .collect()
+}
+
+/// `GUILD` is still read so single-guild deployments keep working unchanged.
+fn get_guilds() -> HashSet<Id<GuildMarker>> {
+    let mut guilds = parse_id_list("GUILDS");
+    guilds.extend(parse_id_list::<GuildMarker>("GUILD"));
+    assert!(!guilds.is_empty(), "GUILDS must list at least one guild!");
+    guilds
+}
+
+/// Sizes are configured in mebibytes. WebP can't be wider or taller than
+/// 16383 pixels, so larger images couldn't be converted anyway.
+fn get_upload_limits() -> UploadLimits {
+    const MIB: usize = 1024 * 1024;
+    let mebibytes = |name, default: usize| {
+        valk_utils::parse_var_or(name, default)
+            .checked_mul(MIB)
+            .unwrap_or_else(|| panic!("{name} is too large!"))
+    };
+    UploadLimits {
+        file_bytes: mebibytes("MAX_FILE_MB", 25),
+        request_bytes: mebibytes("MAX_REQUEST_MB", 100),
+        dimension: valk_utils::parse_var_or("MAX_IMAGE_DIMENSION", 16383),
+        decoded_bytes: mebibytes("MAX_DECODED_MB", 256) as u64,
+    }
+}
+
+/// Defaults to one conversion per CPU, as they're CPU-bound anyway.
+fn get_max_conversions() -> usize {
+    let default = std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+    let max = valk_utils::parse_var_or("MAX_CONVERSIONS", default);
+    assert!(max > 0, "MAX_CONVERSIONS must be at least 1!");
+    max
+}
+
+fn get_access_policy() -> AccessPolicy {
+    let permissions =
+        std::env::var("REQUIRED_PERMISSIONS").map_or(Permissions::MODERATE_MEMBERS, |names| {
+            names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    Permissions::from_name(name).unwrap_or_else(|| {
+                        panic!("Unknown permission {name} in REQUIRED_PERMISSIONS!")
+                    })
+                })
+                .collect()
+        });
+    AccessPolicy {
+        permissions,
+        allowed_roles: parse_id_list("ALLOWED_ROLES"),
+        denied_roles: parse_id_list("DENIED_ROLES"),
+        allowed_users: parse_id_list("ALLOWED_USERS"),
+        denied_users: parse_id_list("DENIED_USERS"),
+    }
+}
+
 fn get_bucket() -> Bucket {
     trace!("Connecting to S3");
     let name: String = parse_var("BUCKET_NAME");
@@ -91,6 +192,8 @@ fn get_http() -> Client {
             env!("CARGO_PKG_VERSION"),
             " (+https://github.com/randomairborne/mod-images)"
         ))
+        .dns_resolver(Arc::new(crate::fetch::PublicResolver))
+        .redirect(crate::fetch::redirect_policy())
         .build()
         .unwrap()
 }
@@ -1,31 +1,519 @@
+use std::io::Cursor;
+
 use axum::body::Bytes;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    error::LimitErrorKind,
+    metadata::Orientation,
+    AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageError, ImageFormat, ImageReader,
+    Limits,
+};
+use sha2::{Digest, Sha256};
+use tokio::task::JoinSet;
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
 
-use crate::{AppState, Error};
+use crate::{
+    collection::{allocate_seqs, append_to_manifest, AttachmentRecord, Manifest, Origin},
+    sanitize::{sanitize, Metadata},
+    AppState, Error,
+};
 
-#[instrument(skip_all)]
-pub async fn upload(state: AppState, image: Bytes) -> Result<String, Error> {
-    let id = crate::randstring(16);
-    trace!(id, "Creating single-image upload entry");
-    upload_raw(state, &id, 0, image).await?;
-    Ok(id)
+const WEBP_QUALITY: f32 = 80.0;
+
+/// Bounds on what we'll take in, so one crafted file can't exhaust the
+/// container's memory. They apply to web uploads and downloads alike.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadLimits {
+    /// Largest single file, in bytes.
+    pub file_bytes: usize,
+    /// Largest web upload request, in bytes, across all of its files.
+    pub request_bytes: usize,
+    /// Largest width or height, in pixels.
+    pub dimension: u32,
+    /// Most memory decoding one image may take, in bytes, counting every
+    /// frame of an animation.
+    pub decoded_bytes: u64,
+}
+
+impl UploadLimits {
+    /// Refuse a file, or the part of one read so far, once it's over the limit.
+    pub const fn check_size(&self, len: usize) -> Result<(), Error> {
+        if len > self.file_bytes {
+            return Err(Error::TooLarge("file size"));
+        }
+        Ok(())
+    }
+
+    fn decoder_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.dimension);
+        limits.max_image_height = Some(self.dimension);
+        limits.max_alloc = Some(self.decoded_bytes);
+        limits
+    }
+}
+
+/// A file posted through the web interface.
+pub struct File {
+    pub name: Option<String>,
+    pub data: Bytes,
+}
+
+/// Convert and store `files` in collection `id` concurrently, after anything it
+/// already holds. `manifest` is only stored as is if the collection is new.
+/// Returns the sequence numbers that were stored, or the first error if none were.
+#[instrument(skip(state, manifest, files), fields(files = files.len()))]
+pub async fn add_files(
+    state: &AppState,
+    id: &str,
+    mut manifest: Manifest,
+    files: Vec<File>,
+) -> Result<Vec<u64>, Error> {
+    if files.is_empty() {
+        return Err(Error::NoFiles);
+    }
+    let seqs = allocate_seqs(state, id, files.len() as u64).await?;
+    let mut set = JoinSet::new();
+    for (seq, file) in seqs.zip(files) {
+        let record = AttachmentRecord {
+            seq,
+            filename: file.name,
+            content_type: image::guess_format(&file.data)
+                .ok()
+                .map(|f| f.to_mime_type().to_owned()),
+            size: file.data.len() as u64,
+            origin: Origin::Attachment,
+            url: None,
+            metadata: Vec::new(),
+            sha256: None,
+        };
+        let state = state.clone();
+        let id = id.to_owned();
+        set.spawn(async move {
+            let mut record = record;
+            let stored = upload_raw(state, &id, seq, file.data).await?;
+            record.metadata = stored.metadata;
+            record.sha256 = Some(stored.sha256);
+            Ok::<_, Error>(record)
+        });
+    }
+
+    let mut stored = Vec::new();
+    let mut first_error = None;
+    while let Some(res) = set.join_next().await {
+        match res.map_err(Error::from).and_then(|res| res) {
+            Ok(record) => stored.push(record),
+            Err(source) => {
+                error!(?source, "Failed to store uploaded file");
+                first_error.get_or_insert(source);
+            }
+        }
+    }
+    if stored.is_empty() {
+        return Err(first_error.unwrap_or(Error::NoFiles));
+    }
+    stored.sort_by_key(|record| record.seq);
+    let seqs = stored.iter().map(|record| record.seq).collect();
+    manifest.attachments.extend(stored);
+    append_to_manifest(state, id, manifest).await?;
+    Ok(seqs)
+}
+
+/// What's recorded about an image once it's stored.
+pub struct Stored {
+    /// The kinds of metadata that were removed from it.
+    pub metadata: Vec<Metadata>,
+    /// Hex SHA-256 of the file as it was received.
+    pub sha256: String,
 }
 
+/// Convert and store one image, and its original if those are kept.
 #[instrument(skip(state, image))]
-pub async fn upload_raw(state: AppState, id: &str, seq: u64, image: Bytes) -> Result<(), Error> {
-    let webp = tokio::task::spawn_blocking(move || convert_image(image)).await??;
+pub async fn upload_raw(
+    state: AppState,
+    id: &str,
+    seq: u64,
+    image: Bytes,
+) -> Result<Stored, Error> {
+    let limits = state.limits;
+    // Each conversion may use the whole decode budget, so only so many run at once.
+    let permit = state.conversions.clone().acquire_owned().await?;
+    let (webp, sanitized, sha256) = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        limits.check_size(image.len())?;
+        let sha256 = hex::encode(Sha256::digest(&image));
+        let sanitized = sanitize(&image, limits.decoder_limits());
+        let webp = convert_image(&image, sanitized.orientation, &limits)?;
+        Ok::<_, Error>((webp, sanitized, sha256))
+    })
+    .await??;
     trace!("Encoded webp, uploading");
     state
         .bucket
         .put_object_with_content_type(format!("{id}/{seq}.webp"), &webp, "image/webp")
         .await?;
+    if state.keep_originals {
+        if let Some(data) = sanitized.data {
+            // The preview is already stored and is what moderators look at, so
+            // losing the original shouldn't lose the image.
+            if let Err(source) = upload_original(&state, id, seq, &data, &sha256).await {
+                warn!(
+                    ?source,
+                    "Failed to store original, keeping only the preview"
+                );
+            }
+        } else {
+            warn!(
+                found = ?sanitized.found,
+                "Not keeping original, its metadata couldn't be removed"
+            );
+        }
+    }
+    Ok(Stored {
+        metadata: sanitized.found,
+        sha256,
+    })
+}
+
+/// Store the bytes we were given, less their metadata, so the file a user
+/// posted can be produced if the lossy rendition is ever disputed. `digest`
+/// is of the file as it was received, so it still matches the poster's copy.
+#[instrument(skip(state, data))]
+async fn upload_original(
+    state: &AppState,
+    id: &str,
+    seq: u64,
+    data: &[u8],
+    digest: &str,
+) -> Result<(), Error> {
+    let format = image::guess_format(data).ok();
+    let content_type = format.map_or("application/octet-stream", |f| f.to_mime_type());
+    let extension = format
+        .and_then(|f| f.extensions_str().first().copied())
+        .unwrap_or("bin");
+    trace!(content_type, digest, "Uploading original");
+    state
+        .bucket
+        .put_object_builder(original_key(id, seq, extension), data)
+        .with_content_type(content_type)
+        .with_metadata("sha256", digest)?
+        .execute()
+        .await?;
     Ok(())
 }
 
+fn original_key(id: &str, seq: u64, extension: &str) -> String {
+    format!("{id}/original/{seq}.{extension}")
+}
+
 #[instrument(skip_all)]
-pub fn convert_image(data: Bytes) -> Result<Vec<u8>, Error> {
-    let image = image::load_from_memory(&data)?;
-    trace!("Loaded image");
+pub fn convert_image(
+    data: &Bytes,
+    orientation: Orientation,
+    limits: &UploadLimits,
+) -> Result<Vec<u8>, Error> {
+    limits.check_size(data.len())?;
+    decode_and_encode(data, orientation, &limits.decoder_limits()).map_err(|source| match source {
+        Error::Image(ImageError::Limits(limit)) => match limit.kind() {
+            LimitErrorKind::DimensionError => Error::TooLarge("dimensions"),
+            _ => Error::TooLarge("decoded size"),
+        },
+        source => source,
+    })
+}
+
+fn decode_and_encode(
+    data: &Bytes,
+    orientation: Orientation,
+    limits: &Limits,
+) -> Result<Vec<u8>, Error> {
+    if let Some(frames) = animation_frames(data, orientation, limits)? {
+        trace!(frames = frames.len(), ?orientation, "Loaded animated image");
+        return encode_animation(&frames);
+    }
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(ImageError::IoError)?;
+    reader.limits(limits.clone());
+    let mut image = reader.decode()?;
+    image.apply_orientation(orientation);
+    trace!(?orientation, "Loaded image");
     let encoder = webp::Encoder::from_image(&image).map_err(|e| Error::WebPStr(e.to_string()))?;
-    let bytes = encoder.encode(80.0).to_vec();
+    let bytes = encoder.encode(WEBP_QUALITY).to_vec();
     Ok(bytes)
 }
+
+/// Decode every frame of a GIF, animated WebP or APNG, turned to display the
+/// right way up. Returns `None` for formats that can't be animated and for
+/// images with only a single frame. The decoders only bound a single frame,
+/// so the frames kept, and the copy each is turned through, are counted
+/// against `limits` here.
+fn animation_frames(
+    data: &[u8],
+    orientation: Orientation,
+    limits: &Limits,
+) -> Result<Option<Vec<Frame>>, Error> {
+    let Ok(format) = image::guess_format(data) else {
+        return Ok(None);
+    };
+    let cursor = Cursor::new(data);
+    let frames = match format {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(cursor)?;
+            decoder.set_limits(limits.clone())?;
+            decoder.into_frames()
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(cursor)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.set_limits(limits.clone())?;
+            decoder.into_frames()
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::with_limits(cursor, limits.clone())?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.apng()?.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    let mut budget = limits.clone();
+    let mut collected = Vec::new();
+    for frame in frames {
+        let mut frame = frame?;
+        let len = frame.buffer().as_raw().len();
+        budget.reserve_usize(len)?;
+        if orientation != Orientation::NoTransforms {
+            budget.reserve_usize(len)?;
+            frame = orient_frame(frame, orientation);
+            budget.free_usize(len);
+        }
+        collected.push(frame);
+    }
+    Ok((collected.len() > 1).then_some(collected))
+}
+
+/// Animation frames are decoded onto the whole canvas, so turning each of
+/// them turns the animation.
+fn orient_frame(frame: Frame, orientation: Orientation) -> Frame {
+    let delay = frame.delay();
+    let mut image = DynamicImage::ImageRgba8(frame.into_buffer());
+    image.apply_orientation(orientation);
+    Frame::from_parts(image.into_rgba8(), 0, 0, delay)
+}
+
+fn encode_animation(frames: &[Frame]) -> Result<Vec<u8>, Error> {
+    let Some(first) = frames.first() else {
+        return Err(Error::WebPStr("Animation has no frames".to_string()));
+    };
+    let (width, height) = first.buffer().dimensions();
+    let mut config =
+        WebPConfig::new().map_err(|()| Error::WebPStr("Failed to create config".to_string()))?;
+    config.quality = WEBP_QUALITY;
+
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    let mut timestamp: i32 = 0;
+    for frame in frames {
+        let buffer = frame.buffer();
+        if buffer.dimensions() != (width, height) {
+            return Err(Error::WebPStr(
+                "Animation frames have inconsistent dimensions".to_string(),
+            ));
+        }
+        encoder.add_frame(AnimFrame::from_rgba(
+            buffer.as_raw(),
+            width,
+            height,
+            timestamp,
+        ));
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay = i32::try_from(playback_delay_ms(numer / denom.max(1))).unwrap_or(i32::MAX);
+        timestamp = timestamp.saturating_add(delay);
+    }
+
+    let mut webp = encoder
+        .try_encode()
+        .map_err(|e| Error::WebPStr(format!("{e:?}")))?
+        .to_vec();
+    set_final_frame_duration(&mut webp, timestamp.unsigned_abs());
+    Ok(webp)
+}
+
+/// Browsers play frames of 10ms or less at 100ms, as many old GIFs set no
+/// delay at all, so do the same to keep the animation at the speed people saw.
+const fn playback_delay_ms(delay_ms: u32) -> u32 {
+    const MIN_DELAY_MS: u32 = 10;
+    const CLAMPED_DELAY_MS: u32 = 100;
+    if delay_ms <= MIN_DELAY_MS {
+        CLAMPED_DELAY_MS
+    } else {
+        delay_ms
+    }
+}
+
+/// libwebp is never told when the final frame ends, so it guesses an average
+/// duration. Rewrite the last `ANMF` chunk so the animation keeps its original
+/// total length.
+fn set_final_frame_duration(webp: &mut [u8], total_ms: u32) {
+    const DURATION_OFFSET: usize = 12;
+    const MAX_DURATION: u32 = (1 << 24) - 1;
+
+    let mut offset = 12;
+    let mut elapsed: u32 = 0;
+    let mut last_frame: Option<(usize, u32)> = None;
+    while offset + 8 <= webp.len() {
+        let size = u32::from_le_bytes([
+            webp[offset + 4],
+            webp[offset + 5],
+            webp[offset + 6],
+            webp[offset + 7],
+        ]) as usize;
+        let payload = offset + 8;
+        if &webp[offset..offset + 4] == b"ANMF"
+            && size >= DURATION_OFFSET + 3
+            && payload + size <= webp.len()
+        {
+            let at = payload + DURATION_OFFSET;
+            if let Some((_, duration)) = last_frame {
+                elapsed = elapsed.saturating_add(duration);
+            }
+            let duration = u32::from_le_bytes([webp[at], webp[at + 1], webp[at + 2], 0]);
+            last_frame = Some((at, duration));
+        }
+        offset = payload + size + (size & 1);
+    }
+
+    if let Some((at, _)) = last_frame {
+        let duration = total_ms.saturating_sub(elapsed).min(MAX_DURATION);
+        webp[at..at + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A RIFF header followed by one `ANMF` chunk per duration, each with
+    /// just enough payload to hold its frame header.
+    fn animation(durations: &[u32]) -> Vec<u8> {
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        for duration in durations {
+            webp.extend_from_slice(b"ANMF");
+            webp.extend_from_slice(&16u32.to_le_bytes());
+            let mut payload = [0u8; 16];
+            payload[12..15].copy_from_slice(&duration.to_le_bytes()[..3]);
+            webp.extend_from_slice(&payload);
+        }
+        webp
+    }
+
+    fn durations(webp: &[u8]) -> Vec<u32> {
+        webp.windows(4)
+            .enumerate()
+            .filter(|(_, id)| *id == b"ANMF")
+            .map(|(at, _)| {
+                let at = at + 8 + 12;
+                u32::from_le_bytes([webp[at], webp[at + 1], webp[at + 2], 0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn final_frame_gets_the_remaining_time() {
+        let mut webp = animation(&[100, 200, 150]);
+        set_final_frame_duration(&mut webp, 1000);
+        assert_eq!(durations(&webp), [100, 200, 700]);
+    }
+
+    #[test]
+    fn final_frame_duration_never_underflows() {
+        let mut webp = animation(&[500, 500]);
+        set_final_frame_duration(&mut webp, 200);
+        assert_eq!(durations(&webp), [500, 0]);
+    }
+
+    #[test]
+    fn no_frames_is_left_alone() {
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8 \0\0\0\0".to_vec();
+        let before = webp.clone();
+        set_final_frame_duration(&mut webp, 1000);
+        assert_eq!(webp, before);
+    }
+
+    fn gif(frames: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut data);
+            for _ in 0..frames {
+                let buffer = image::RgbaImage::new(4, 4);
+                encoder.encode_frame(Frame::new(buffer)).unwrap();
+            }
+        }
+        data
+    }
+
+    fn alloc_limit(bytes: u64) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_alloc = Some(bytes);
+        limits
+    }
+
+    #[test]
+    fn turning_frames_counts_against_the_budget() {
+        // Two 4x4 RGBA frames take 128 bytes, and turning one needs 64 more.
+        let data = gif(2);
+        let limits = alloc_limit(160);
+        let frames = animation_frames(&data, Orientation::NoTransforms, &limits).unwrap();
+        assert_eq!(frames.map(|frames| frames.len()), Some(2));
+        assert!(matches!(
+            animation_frames(&data, Orientation::Rotate90, &limits),
+            Err(Error::Image(ImageError::Limits(_)))
+        ));
+        let frames = animation_frames(&data, Orientation::Rotate90, &alloc_limit(192)).unwrap();
+        assert_eq!(frames.map(|frames| frames.len()), Some(2));
+    }
+
+    #[test]
+    fn turned_images_swap_their_dimensions() {
+        let mut data = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image::RgbImage::new(4, 2))
+            .write_to(&mut data, ImageFormat::Png)
+            .unwrap();
+        let data = Bytes::from(data.into_inner());
+        let webp = decode_and_encode(&data, Orientation::Rotate90, &Limits::default()).unwrap();
+        let preview = image::load_from_memory_with_format(&webp, ImageFormat::WebP).unwrap();
+        assert_eq!((preview.width(), preview.height()), (2, 4));
+    }
+
+    #[test]
+    fn files_up_to_the_limit_are_accepted() {
+        let limits = UploadLimits {
+            file_bytes: 10,
+            request_bytes: 100,
+            dimension: 100,
+            decoded_bytes: 1000,
+        };
+        assert!(limits.check_size(0).is_ok());
+        assert!(limits.check_size(10).is_ok());
+        assert!(matches!(
+            limits.check_size(11),
+            Err(Error::TooLarge("file size"))
+        ));
+    }
+
+    #[test]
+    fn originals_sit_beside_previews() {
+        assert_eq!(original_key("abc", 3, "png"), "abc/original/3.png");
+    }
+
+    #[test]
+    fn short_delays_play_like_browsers() {
+        assert_eq!(playback_delay_ms(0), 100);
+        assert_eq!(playback_delay_ms(10), 100);
+        assert_eq!(playback_delay_ms(20), 20);
+        assert_eq!(playback_delay_ms(1000), 1000);
+    }
+}